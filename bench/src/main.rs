@@ -0,0 +1,132 @@
+//! Per-event latency of the cryptographic operations on the `handle_input` and
+//! `handle_handler` paths, comparing the cipher contexts of the connections
+//! (`__cipher.rs`) with calling `reactive_crypto` with a copy of the key.
+//!
+//! Usage: `cargo run --release [-- <iterations>]`
+
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use reactive_crypto::Encryption;
+
+#[path = "../../rustsgxgen/stubs/__cipher.rs"]
+#[allow(dead_code)]
+mod cipher;
+
+use cipher::Cipher;
+
+const DEFAULT_ITERATIONS : u32 = 100_000;
+const PAYLOAD_SIZES : [usize; 4] = [16, 128, 1024, 8192];
+const KEY : [u8; 16] = [0x2a; 16];
+
+/// Connection state as it was before the cipher contexts: the key and the
+/// encryption type are cloned for each event
+struct KeyConnection {
+    key : Vec<u8>,
+    encryption : Encryption
+}
+
+/// Connection state holding a prepared cipher context
+struct CipherConnection {
+    cipher : Arc<Cipher>
+}
+
+/// Associated data used by `reactive_crypto`, with a fixed IV
+fn associated_data(epoch : u32, nonce : u64) -> Vec<u8> {
+    let mut ad = Vec::with_capacity(12);
+    ad.extend_from_slice(&epoch.to_be_bytes());
+    ad.extend_from_slice(&nonce.to_be_bytes());
+    ad
+}
+
+/// Average time of `f` in nanoseconds
+fn measure(iterations : u32, mut f : impl FnMut()) -> f64 {
+    // warm up
+    for _ in 0..iterations / 10 {
+        f();
+    }
+
+    let start = Instant::now();
+    for _ in 0..iterations {
+        f();
+    }
+
+    start.elapsed().as_nanos() as f64 / iterations as f64
+}
+
+fn report(path : &str, size : usize, before : f64, after : f64) {
+    println!("{:<15} {:>7} {:>12.0} {:>12.0} {:>9.1}%",
+        path, size, before, after, (before - after) / before * 100.0);
+}
+
+fn main() {
+    let iterations = std::env::args().nth(1)
+        .map(|i| i.parse().expect("iterations must be a number"))
+        .unwrap_or(DEFAULT_ITERATIONS);
+
+    let key_conn = Mutex::new(KeyConnection {
+        key : KEY.to_vec(),
+        encryption : Encryption::Aes
+    });
+
+    let cipher_conn = Mutex::new(CipherConnection {
+        cipher : Arc::new(Cipher::new(&KEY, &Encryption::Aes).expect("invalid key"))
+    });
+
+    let ad = associated_data(0, 0);
+    let resp_ad = associated_data(0, 1);
+
+    println!("{:<15} {:>7} {:>12} {:>12} {:>10}", "path", "bytes", "before (ns)", "after (ns)", "gain");
+
+    for size in PAYLOAD_SIZES.iter().cloned() {
+        let data = vec![0x55u8; size];
+        let payload = reactive_crypto::encrypt(&data, &KEY, &ad, &Encryption::Aes)
+            .expect("encryption failed");
+        // the cipher contexts derive the IV from the epoch and nonce
+        let mut cipher_payload = Vec::new();
+        cipher_conn.lock().unwrap().cipher.encrypt(&data, 0, 0, &mut cipher_payload)
+            .expect("encryption failed");
+
+        // handle_input: decrypt the payload while holding the connection
+        let before = measure(iterations, || {
+            let conn = key_conn.lock().unwrap();
+            let data = reactive_crypto::decrypt(&payload, &conn.key.clone(), &ad,
+                                                &conn.encryption.clone()).unwrap();
+            assert_eq!(data.len(), size);
+        });
+
+        let after = measure(iterations, || {
+            let conn = cipher_conn.lock().unwrap();
+            let data = conn.cipher.decrypt(&cipher_payload, 0, 0).unwrap();
+            assert_eq!(data.len(), size);
+        });
+
+        report("handle_input", size, before, after);
+
+        // handle_handler: decrypt the request, then encrypt the response after
+        // releasing the connection
+        let before = measure(iterations, || {
+            let conn = key_conn.lock().unwrap();
+            let key = conn.key.clone();
+            let encryption = conn.encryption.clone();
+            drop(conn);
+
+            let data = reactive_crypto::decrypt(&payload, &key, &ad, &encryption).unwrap();
+            let response = reactive_crypto::encrypt(&data, &key, &resp_ad, &encryption).unwrap();
+            assert_eq!(response.len(), payload.len());
+        });
+
+        let after = measure(iterations, || {
+            let conn = cipher_conn.lock().unwrap();
+            let cipher = conn.cipher.clone();
+            drop(conn);
+
+            let data = cipher.decrypt(&cipher_payload, 0, 0).unwrap();
+            let mut response = Vec::new();
+            cipher.encrypt(&data, 0, 1, &mut response).unwrap();
+            assert_eq!(response.len(), payload.len());
+        });
+
+        report("handle_handler", size, before, after);
+    }
+}
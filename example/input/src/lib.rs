@@ -1,4 +1,5 @@
 // Imports and other stuff
+mod sensors;
 
 //@ sm_output(button_pressed)
 //@ sm_output(output1)
@@ -9,16 +10,19 @@
 pub fn press_button(_data : &[u8]) -> ResultMessage {
     debug!("ENTRYPOINT: press_button");
 
-    button_pressed(&[]);
-
-    success(None)
+    match button_pressed(&[]) {
+        Ok(_)   => success(None),
+        Err(_)  => failure(ResultCode::InternalError, None)
+    }
 }
 
 //@ sm_input
 pub fn input1(data : &[u8]) {
     info!("INPUT: input1");
 
-    output1(data);
+    if let Err(e) = output1(data) {
+        error!("{}", e);
+    }
 }
 
 //@ sm_handler
@@ -29,3 +33,6 @@ pub fn handler_value(_data : &[u8]) -> Vec<u8> {
 }
 
 // User-defined functions and other stuff
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,187 @@
+// Tests of the generated code, run by the CI on the output module (native runner).
+// Events and management commands are sent to `handle_entrypoint`, as the Event
+// Manager would do. The IDs are those of `sm_ids.lock`
+
+use std::convert::TryInto;
+use std::sync::{Mutex, MutexGuard};
+use aes_gcm::{Aes128Gcm, KeyInit};
+use aes_gcm::aead::{Aead, Payload};
+use reactive_net::{ResultCode, ResultMessage};
+use crate::__authentic_execution::authentic_execution::{handle_entrypoint, restore_state, PERSIST_FILE};
+use crate::__run::MODULE_KEY;
+
+const SET_KEY : u16 = 0;
+const HANDLE_INPUT : u16 = 3;
+const HANDLE_HANDLER : u16 = 4;
+const GET_NONCE : u16 = 16383;
+
+const INPUT1 : u16 = 0;
+const NEW_READING : u16 = 1;
+const NUM_READINGS : u16 = 6;
+const HANDLER_VALUE : u16 = 49152;
+const LAST_READING : u16 = 49153;
+
+// the state of the module is global: tests are run one at a time
+static LOCK : Mutex<()> = Mutex::new(());
+
+fn lock() -> MutexGuard<'static, ()> {
+    LOCK.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn call(entry : u16, data : &[u8]) -> ResultMessage {
+    let mut payload = entry.to_be_bytes().to_vec();
+    payload.extend_from_slice(data);
+    handle_entrypoint(&payload)
+}
+
+fn is_ok(result : &ResultMessage) -> bool {
+    matches!(result.get_code(), ResultCode::Ok)
+}
+
+fn header(epoch : u32, nonce : u64) -> [u8; 12] {
+    let mut header = [0u8; 12];
+    header[..4].copy_from_slice(&epoch.to_be_bytes());
+    header[4..].copy_from_slice(&nonce.to_be_bytes());
+    header
+}
+
+fn encrypt(key : &[u8], iv : [u8; 12], ad : &[u8], plaintext : &[u8]) -> Vec<u8> {
+    Aes128Gcm::new_from_slice(key).unwrap()
+        .encrypt(&iv.into(), Payload { msg : plaintext, aad : ad }).unwrap()
+}
+
+fn decrypt(key : &[u8], iv : [u8; 12], ad : &[u8], cipher : &[u8]) -> Vec<u8> {
+    Aes128Gcm::new_from_slice(key).unwrap()
+        .decrypt(&iv.into(), Payload { msg : cipher, aad : ad }).unwrap()
+}
+
+fn management_nonce() -> u64 {
+    let result = call(GET_NONCE, &[0u8; 16]);
+    assert!(is_ok(&result));
+    u64::from_be_bytes(result.get_payload().unwrap()[..8].try_into().unwrap())
+}
+
+/// Create an AES connection to `index`, as the deployer would do
+fn set_key(conn_id : u16, index : u16, window : u8, key : &[u8]) {
+    let nonce = management_nonce();
+
+    let mut ad = vec![0u8];
+    ad.extend_from_slice(&conn_id.to_be_bytes());
+    ad.extend_from_slice(&index.to_be_bytes());
+    ad.push(window);
+
+    let mut payload = ad.clone();
+    ad.extend_from_slice(&nonce.to_be_bytes());
+    payload.extend_from_slice(&encrypt(&MODULE_KEY, header(0, nonce), &ad, key));
+
+    assert!(is_ok(&call(SET_KEY, &payload)));
+    // the command cannot be replayed
+    assert!(!is_ok(&call(SET_KEY, &payload)));
+}
+
+/// Send an event of a windowed connection: [conn_id - nonce - cipher]
+fn send_event(conn_id : u16, key : &[u8], nonce : u64, data : &[u8]) -> ResultMessage {
+    let mut payload = conn_id.to_be_bytes().to_vec();
+    payload.extend_from_slice(&nonce.to_be_bytes());
+    payload.extend_from_slice(&encrypt(key, header(0, nonce), &header(0, nonce), data));
+    call(HANDLE_INPUT, &payload)
+}
+
+/// Call a handler with message `nonce` of the connection, and return the decrypted [code - data]
+fn send_request(conn_id : u16, key : &[u8], nonce : u64, data : &[u8]) -> Vec<u8> {
+    let mut payload = conn_id.to_be_bytes().to_vec();
+    payload.extend_from_slice(&encrypt(key, header(0, nonce), &header(0, nonce), data));
+
+    let result = call(HANDLE_HANDLER, &payload);
+    assert!(is_ok(&result));
+    decrypt(key, header(0, nonce + 1), &header(0, nonce + 1), result.get_payload().unwrap())
+}
+
+#[test]
+fn strict_ordering() {
+    let _lock = lock();
+    let key = [1u8; 16];
+    set_key(1, INPUT1, 0, &key);
+
+    let event = |nonce : u64| {
+        let mut payload = 1u16.to_be_bytes().to_vec();
+        payload.extend_from_slice(&encrypt(&key, header(0, nonce), &header(0, nonce), b"event"));
+        call(HANDLE_INPUT, &payload)
+    };
+
+    assert!(is_ok(&event(0)));
+    assert!(!is_ok(&event(0)));
+    assert!(!is_ok(&event(2)));
+    assert!(is_ok(&event(1)));
+}
+
+#[test]
+fn sliding_window() {
+    let _lock = lock();
+    let key = [2u8; 16];
+    set_key(2, INPUT1, 4, &key);
+
+    // out of order, but within the window
+    for nonce in &[1, 0, 3, 2] {
+        assert!(is_ok(&send_event(2, &key, *nonce, b"event")), "nonce {}", nonce);
+    }
+
+    // replays are rejected
+    assert!(!is_ok(&send_event(2, &key, 2, b"event")));
+
+    // a gap is accepted, then nonces older than the window are rejected
+    assert!(is_ok(&send_event(2, &key, 10, b"event")));
+    assert!(!is_ok(&send_event(2, &key, 6, b"event")));
+    assert!(is_ok(&send_event(2, &key, 7, b"event")));
+}
+
+#[test]
+fn typed_items() {
+    let _lock = lock();
+    let key = [3u8; 16];
+    set_key(3, NEW_READING, 1, &key);
+    set_key(4, LAST_READING, 0, &key);
+
+    // the handler fails if the sensor has no readings
+    assert_eq!(send_request(4, &key, 0, &bincode::serialize(&7u16).unwrap()),
+        vec![ResultCode::BadRequest as u8]);
+
+    let reading = crate::sensors::Reading { id : 7, value : 1.5 };
+    assert!(is_ok(&send_event(3, &key, 0, &bincode::serialize(&reading).unwrap())));
+
+    let response = send_request(4, &key, 2, &bincode::serialize(&7u16).unwrap());
+    assert_eq!(response[0], ResultCode::Ok as u8);
+    let last : crate::sensors::Reading = bincode::deserialize(&response[1..]).unwrap();
+    assert_eq!((last.id, last.value), (7, 1.5));
+
+    // an input that cannot be deserialized is rejected
+    assert!(!is_ok(&send_event(3, &key, 1, &[1])));
+
+    // typed entry point
+    let result = call(NUM_READINGS, &bincode::serialize(&7u16).unwrap());
+    assert!(is_ok(&result));
+    assert_eq!(bincode::deserialize::<u32>(result.get_payload().unwrap()).unwrap(), 1);
+
+    // raw handler
+    set_key(5, HANDLER_VALUE, 0, &key);
+    assert_eq!(send_request(5, &key, 0, &[]), vec![ResultCode::Ok as u8, 1, 2, 3, 4]);
+}
+
+#[test]
+fn restore_persisted_state() {
+    if PERSIST_FILE.is_none() {
+        return
+    }
+
+    let _lock = lock();
+    let key = [6u8; 16];
+    set_key(6, INPUT1, 8, &key);
+    assert!(is_ok(&send_event(6, &key, 0, b"event")));
+    let nonce = management_nonce();
+
+    // restart: the connections are restored at their high-water mark
+    restore_state().unwrap();
+    assert_eq!(management_nonce(), nonce);
+    assert!(!is_ok(&send_event(6, &key, 1, b"event")));
+    assert!(is_ok(&send_event(6, &key, 1025, b"event")));
+}
@@ -0,0 +1,548 @@
+//! Macros to declare inputs, entry points, handlers, outputs and requests of a
+//! software module, as an alternative to the `//@ sm_*` annotations.
+//!
+//! The signatures of the annotated functions are checked at compile time.
+//! rust-sgx-gen compiles the module once with `SM_METADATA_FILE` set: in that
+//! case, each macro also appends a description of its item to the file (one JSON
+//! object per line), which the generator uses to register the item.
+//!
+//! All the macros take an optional explicit ID, e.g., `#[sm_input(id = 7)]` or
+//! `sm_output!(name, T, id = 16390)`.
+
+extern crate proc_macro;
+
+use std::io::Write;
+
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use quote::{format_ident, quote, ToTokens};
+use syn::parse::{Parse, ParseStream};
+use syn::spanned::Spanned;
+use syn::{parse_macro_input, Error, FnArg, GenericArgument, GenericParam, Ident, ItemFn,
+    LitInt, PathArguments, ReturnType, Token, Type, Visibility};
+
+/// Environment variable set by rust-sgx-gen when collecting the metadata
+const METADATA_ENV : &str = "SM_METADATA_FILE";
+
+#[derive(Clone, Copy)]
+enum Kind {
+    Input,
+    Entry,
+    Handler,
+    Output,
+    Request
+}
+
+impl Kind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Kind::Input     => "input",
+            Kind::Entry     => "entry",
+            Kind::Handler   => "handler",
+            Kind::Output    => "output",
+            Kind::Request   => "request"
+        }
+    }
+
+    fn plural(&self) -> &'static str {
+        match self {
+            Kind::Input     => "inputs",
+            Kind::Entry     => "entry points",
+            Kind::Handler   => "handlers",
+            Kind::Output    => "outputs",
+            Kind::Request   => "requests"
+        }
+    }
+}
+
+/// Description of an item, written to the metadata file
+struct Metadata {
+    kind : Kind,
+    name : String,
+    typed : bool,
+    arg : Option<String>,
+    ret : Option<String>,
+    id : Option<u16>,
+    span : Span
+}
+
+/// Declares an input: `pub fn name(data : &[u8])`, or `pub fn name(value : T)` for typed data
+#[proc_macro_attribute]
+pub fn sm_input(attr : TokenStream, item : TokenStream) -> TokenStream {
+    let func = parse_macro_input!(item as ItemFn);
+    expand_fn(Kind::Input, attr, func)
+}
+
+/// Declares an entry point: `pub fn name(data : &[u8]) -> ResultMessage`,
+/// or `pub fn name(value : T) -> R` for typed data
+#[proc_macro_attribute]
+pub fn sm_entry(attr : TokenStream, item : TokenStream) -> TokenStream {
+    let func = parse_macro_input!(item as ItemFn);
+    expand_fn(Kind::Entry, attr, func)
+}
+
+/// Declares a handler: `pub fn name(data : &[u8]) -> Vec<u8>`,
+/// or `pub fn name(value : T) -> R` for typed data. Handlers can fail by
+/// returning `Result<Vec<u8>, ResultCode>` (or `Result<R, ResultCode>`)
+#[proc_macro_attribute]
+pub fn sm_handler(attr : TokenStream, item : TokenStream) -> TokenStream {
+    let func = parse_macro_input!(item as ItemFn);
+    expand_fn(Kind::Handler, attr, func)
+}
+
+/// Declares an output: `sm_output!(name)`, or `sm_output!(name, T)` for typed data
+#[proc_macro]
+pub fn sm_output(item : TokenStream) -> TokenStream {
+    let output = parse_macro_input!(item as OutputArgs);
+    let name = &output.name;
+
+    let meta = Metadata {
+        kind : Kind::Output,
+        name : name.to_string(),
+        typed : output.arg.is_some(),
+        arg : output.arg.as_ref().map(type_to_string),
+        ret : None,
+        id : output.id,
+        span : name.span()
+    };
+
+    let auth_exec = auth_exec_path();
+    let tokens = match (&output.arg, collecting()) {
+        (Some(arg), true)   => quote! {
+            pub fn #name(_data : &#arg) -> Result<(), #auth_exec::Error> { unreachable!() }
+        },
+        (None, true)        => quote! {
+            pub fn #name(_data : &[u8]) -> Result<(), #auth_exec::Error> { unreachable!() }
+        },
+        (Some(arg), false)  => quote! {
+            pub fn #name(data : &#arg) -> Result<(), #auth_exec::Error> {
+                crate::debug!(concat!("OUTPUT: ", stringify!(#name)));
+                let id : u16 = crate::__outputs::__ids::#name;
+
+                let data = #auth_exec::encode(data)?;
+                #auth_exec::handle_output(id, &data)
+            }
+        },
+        (None, false)       => quote! {
+            pub fn #name(data : &[u8]) -> Result<(), #auth_exec::Error> {
+                crate::debug!(concat!("OUTPUT: ", stringify!(#name)));
+                let id : u16 = crate::__outputs::__ids::#name;
+
+                #auth_exec::handle_output(id, data)
+            }
+        }
+    };
+
+    expand_declaration(&meta, tokens)
+}
+
+/// Declares a request: `sm_request!(name)`, or `sm_request!(name, T -> R)` for typed data.
+/// Also declares `name_with_timeout`, which takes the timeout of the request
+#[proc_macro]
+pub fn sm_request(item : TokenStream) -> TokenStream {
+    let request = parse_macro_input!(item as RequestArgs);
+    let name = &request.name;
+    let name_with_timeout = format_ident!("{}_with_timeout", name);
+
+    let meta = Metadata {
+        kind : Kind::Request,
+        name : name.to_string(),
+        typed : request.types.is_some(),
+        arg : request.types.as_ref().map(|(arg, _)| type_to_string(arg)),
+        ret : request.types.as_ref().map(|(_, ret)| type_to_string(ret)),
+        id : request.id,
+        span : name.span()
+    };
+
+    let auth_exec = auth_exec_path();
+    let tokens = match (&request.types, collecting()) {
+        (Some((arg, ret)), true)    => quote! {
+            pub fn #name(_data : &#arg) -> Result<#ret, #auth_exec::Error> { unreachable!() }
+            #[allow(dead_code)]
+            pub fn #name_with_timeout(_data : &#arg, _timeout : std::time::Duration)
+                -> Result<#ret, #auth_exec::Error> { unreachable!() }
+        },
+        (None, true)                => quote! {
+            pub fn #name(_data : &[u8]) -> Result<Vec<u8>, #auth_exec::Error> { unreachable!() }
+            #[allow(dead_code)]
+            pub fn #name_with_timeout(_data : &[u8], _timeout : std::time::Duration)
+                -> Result<Vec<u8>, #auth_exec::Error> { unreachable!() }
+        },
+        (Some((arg, ret)), false)   => quote! {
+            pub fn #name(data : &#arg) -> Result<#ret, #auth_exec::Error> {
+                crate::debug!(concat!("REQUEST: ", stringify!(#name)));
+                let id : u16 = crate::__outputs::__ids::#name;
+
+                let response = #auth_exec::handle_request(id, &#auth_exec::encode(data)?)?;
+                #auth_exec::decode(&response).map_err(|_| #auth_exec::Error::BadResponse)
+            }
+
+            #[allow(dead_code)]
+            pub fn #name_with_timeout(data : &#arg, timeout : std::time::Duration)
+                    -> Result<#ret, #auth_exec::Error> {
+                crate::debug!(concat!("REQUEST: ", stringify!(#name)));
+                let id : u16 = crate::__outputs::__ids::#name;
+
+                let response = #auth_exec::handle_request_with_timeout(id, &#auth_exec::encode(data)?,
+                    Some(timeout))?;
+                #auth_exec::decode(&response).map_err(|_| #auth_exec::Error::BadResponse)
+            }
+        },
+        (None, false)               => quote! {
+            pub fn #name(data : &[u8]) -> Result<Vec<u8>, #auth_exec::Error> {
+                crate::debug!(concat!("REQUEST: ", stringify!(#name)));
+                let id : u16 = crate::__outputs::__ids::#name;
+
+                #auth_exec::handle_request(id, data)
+            }
+
+            #[allow(dead_code)]
+            pub fn #name_with_timeout(data : &[u8], timeout : std::time::Duration)
+                    -> Result<Vec<u8>, #auth_exec::Error> {
+                crate::debug!(concat!("REQUEST: ", stringify!(#name)));
+                let id : u16 = crate::__outputs::__ids::#name;
+
+                #auth_exec::handle_request_with_timeout(id, data, Some(timeout))
+            }
+        }
+    };
+
+    expand_declaration(&meta, tokens)
+}
+
+fn expand_fn(kind : Kind, attr : TokenStream, func : ItemFn) -> TokenStream {
+    let result = syn::parse::<IdArg>(attr)
+        .and_then(|id| check_fn(kind, &func, id.0))
+        .and_then(|meta| write_metadata(&meta));
+
+    // the function is always emitted, to avoid further errors where it is used
+    let mut tokens = match result {
+        Ok(_)   => proc_macro2::TokenStream::new(),
+        Err(e)  => e.to_compile_error()
+    };
+
+    func.to_tokens(&mut tokens);
+    tokens.into()
+}
+
+/// The IDs of outputs and requests are generated by rust-sgx-gen in the `__outputs`
+/// module. While collecting the metadata, the IDs are not known yet: the declared
+/// function is only a stub
+fn expand_declaration(meta : &Metadata, tokens : proc_macro2::TokenStream) -> TokenStream {
+    if let Err(e) = write_metadata(meta) {
+        return e.to_compile_error().into()
+    }
+
+    let tokens = quote! {
+        #[allow(dead_code)]
+        #tokens
+    };
+
+    tokens.into()
+}
+
+fn auth_exec_path() -> proc_macro2::TokenStream {
+    quote! { crate::__authentic_execution::authentic_execution }
+}
+
+fn check_fn(kind : Kind, func : &ItemFn, id : Option<u16>) -> Result<Metadata, Error> {
+    let sig = &func.sig;
+    let what = kind.plural();
+
+    // the function is registered by its path, it must be reachable from the crate root
+    if let Visibility::Inherited = func.vis {
+        return Err(Error::new(sig.fn_token.span(), format!("{} must be `pub` or `pub(crate)`", what)))
+    }
+
+    if let Some(token) = &sig.asyncness {
+        return Err(Error::new(token.span(), format!("{} cannot be `async`", what)))
+    }
+
+    if let Some(token) = &sig.unsafety {
+        return Err(Error::new(token.span(), format!("{} cannot be `unsafe`", what)))
+    }
+
+    if let Some(abi) = &sig.abi {
+        return Err(Error::new_spanned(abi, format!("{} cannot declare an ABI", what)))
+    }
+
+    if let Some(variadic) = &sig.variadic {
+        return Err(Error::new_spanned(variadic, format!("{} cannot be variadic", what)))
+    }
+
+    for param in sig.generics.params.iter() {
+        if let GenericParam::Lifetime(_) = param {
+            continue;
+        }
+
+        return Err(Error::new_spanned(param, format!("{} cannot have type or const parameters", what)))
+    }
+
+    if let Some(clause) = &sig.generics.where_clause {
+        return Err(Error::new_spanned(clause, format!("{} cannot have a where clause", what)))
+    }
+
+    if sig.inputs.len() != 1 {
+        let msg = format!("{} must take exactly one argument", what);
+        return match sig.inputs.is_empty() {
+            true    => Err(Error::new(sig.paren_token.span.join(), msg)),
+            false   => Err(Error::new_spanned(&sig.inputs, msg))
+        }
+    }
+
+    let arg = match &sig.inputs[0] {
+        FnArg::Typed(arg)       => &*arg.ty,
+        FnArg::Receiver(arg)    => return Err(Error::new_spanned(arg, format!("{} cannot take `self`", what)))
+    };
+
+    let typed = !is_raw_data(arg);
+    if typed {
+        if let Type::Reference(_) | Type::ImplTrait(_) = arg {
+            return Err(Error::new_spanned(arg,
+                "the argument must be `&[u8]` (raw data) or an owned type implementing `serde::Deserialize`"))
+        }
+    }
+
+    let ret = match &sig.output {
+        ReturnType::Default     => None,
+        ReturnType::Type(_, t)  => Some(&**t)
+    };
+
+    match (kind, ret) {
+        (Kind::Input, Some(t))                                  =>
+            return Err(Error::new_spanned(t, "inputs cannot return a value")),
+        (Kind::Entry, None) if !typed                           =>
+            return Err(Error::new(sig.ident.span(), "entry points taking `&[u8]` must return `ResultMessage`")),
+        (Kind::Entry, Some(t)) if !typed && !is_result_message(t) =>
+            return Err(Error::new_spanned(t, "entry points taking `&[u8]` must return `ResultMessage`")),
+        (Kind::Handler, None)                                   =>
+            return Err(Error::new(sig.ident.span(), "handlers must return a value")),
+        (Kind::Handler, Some(t)) if !typed && !is_vec_u8(handler_value(t)) =>
+            return Err(Error::new_spanned(t,
+                "handlers taking `&[u8]` must return `Vec<u8>` or `Result<Vec<u8>, ResultCode>`")),
+        _                                                       => ()
+    }
+
+    Ok(Metadata {
+        kind,
+        name : sig.ident.to_string(),
+        typed,
+        arg : Some(type_to_string(arg)),
+        ret : ret.map(type_to_string),
+        id,
+        span : sig.ident.span()
+    })
+}
+
+/// `&[u8]`, with any lifetime
+fn is_raw_data(ty : &Type) -> bool {
+    match ty {
+        Type::Reference(r) if r.mutability.is_none()  => match &*r.elem {
+            Type::Slice(s)  => is_ident(&s.elem, "u8"),
+            _               => false
+        },
+        _                                           => false
+    }
+}
+
+fn is_result_message(ty : &Type) -> bool {
+    is_ident(ty, "ResultMessage")
+}
+
+/// Type of the data returned by a handler: `T` for `Result<T, ResultCode>`, `ty` otherwise
+fn handler_value(ty : &Type) -> &Type {
+    let segment = match ty {
+        Type::Path(p)   => match p.path.segments.last() {
+            Some(s) if s.ident == "Result"  => s,
+            _                               => return ty
+        },
+        _               => return ty
+    };
+
+    match &segment.arguments {
+        PathArguments::AngleBracketed(args) if args.args.len() == 2 => match (&args.args[0], &args.args[1]) {
+            (GenericArgument::Type(t), GenericArgument::Type(e)) if is_ident(e, "ResultCode") => t,
+            _                                                                               => ty
+        },
+        _                                                           => ty
+    }
+}
+
+fn is_vec_u8(ty : &Type) -> bool {
+    let segment = match ty {
+        Type::Path(p)   => match p.path.segments.last() {
+            Some(s) if s.ident == "Vec" => s,
+            _                           => return false
+        },
+        _               => return false
+    };
+
+    match &segment.arguments {
+        PathArguments::AngleBracketed(args) if args.args.len() == 1 => match &args.args[0] {
+            GenericArgument::Type(t)    => is_ident(t, "u8"),
+            _                           => false
+        },
+        _                                                           => false
+    }
+}
+
+/// Last segment of a path type, without generic arguments
+fn is_ident(ty : &Type, ident : &str) -> bool {
+    match ty {
+        Type::Path(p)   => match p.path.segments.last() {
+            Some(s)     => s.ident == ident && s.arguments.is_empty(),
+            None        => false
+        },
+        _               => false
+    }
+}
+
+fn type_to_string(ty : &Type) -> String {
+    ty.to_token_stream().to_string()
+}
+
+fn collecting() -> bool {
+    std::env::var_os(METADATA_ENV).is_some()
+}
+
+fn write_metadata(meta : &Metadata) -> Result<(), Error> {
+    let path = match std::env::var_os(METADATA_ENV) {
+        Some(p) => p,
+        None    => return Ok(())
+    };
+
+    let span = meta.span.unwrap();
+    let file = match span.local_file() {
+        Some(f) => f.display().to_string(),
+        None    => span.file()
+    };
+
+    let id = match meta.id {
+        Some(id)    => id.to_string(),
+        None        => "null".to_string()
+    };
+
+    let line = format!("{{\"kind\": {}, \"name\": {}, \"typed\": {}, \"arg\": {}, \"ret\": {}, \
+                        \"id\": {}, \"file\": {}, \"line\": {}}}\n",
+                       json_string(meta.kind.as_str()), json_string(&meta.name), meta.typed,
+                       json_option(&meta.arg), json_option(&meta.ret), id, json_string(&file), span.line());
+
+    std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut f| f.write_all(line.as_bytes()))
+        .map_err(|e| Error::new(meta.span, format!("cannot write metadata: {}", e)))
+}
+
+fn json_string(s : &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+
+    for c in s.chars() {
+        match c {
+            '"'                 => out.push_str("\\\""),
+            '\\'                => out.push_str("\\\\"),
+            c if c < ' '        => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c                   => out.push(c)
+        }
+    }
+
+    out.push('"');
+    out
+}
+
+fn json_option(s : &Option<String>) -> String {
+    match s {
+        Some(s) => json_string(s),
+        None    => "null".to_string()
+    }
+}
+
+/// Optional explicit ID: `id = <u16>`
+struct IdArg(Option<u16>);
+
+impl Parse for IdArg {
+    fn parse(input : ParseStream) -> syn::Result<Self> {
+        if input.is_empty() {
+            return Ok(IdArg(None))
+        }
+
+        let key : Ident = input.parse()?;
+        if key != "id" {
+            return Err(Error::new(key.span(), "expected `id = <ID>`"))
+        }
+
+        input.parse::<Token![=]>()?;
+        let id = input.parse::<LitInt>()?.base10_parse()?;
+
+        if !input.is_empty() {
+            return Err(input.error("unexpected tokens after the ID"))
+        }
+
+        Ok(IdArg(Some(id)))
+    }
+}
+
+/// Whether the next argument is the explicit ID, i.e., `, id = ..`
+fn peek_id(input : ParseStream) -> bool {
+    let fork = input.fork();
+
+    fork.parse::<Token![,]>().is_ok()
+        && fork.parse::<Ident>().is_ok_and(|key| key == "id")
+        && fork.peek(Token![=])
+}
+
+fn parse_id(input : ParseStream) -> syn::Result<Option<u16>> {
+    match input.is_empty() {
+        true    => Ok(None),
+        false   => {
+            input.parse::<Token![,]>()?;
+            input.parse::<IdArg>().map(|id| id.0)
+        }
+    }
+}
+
+struct OutputArgs {
+    name : Ident,
+    arg : Option<Type>,
+    id : Option<u16>
+}
+
+impl Parse for OutputArgs {
+    fn parse(input : ParseStream) -> syn::Result<Self> {
+        let name = input.parse()?;
+        let arg = match input.is_empty() || peek_id(input) {
+            true    => None,
+            false   => {
+                input.parse::<Token![,]>()?;
+                Some(input.parse()?)
+            }
+        };
+
+        Ok(OutputArgs { name, arg, id : parse_id(input)? })
+    }
+}
+
+struct RequestArgs {
+    name : Ident,
+    types : Option<(Type, Type)>,
+    id : Option<u16>
+}
+
+impl Parse for RequestArgs {
+    fn parse(input : ParseStream) -> syn::Result<Self> {
+        let name = input.parse()?;
+        let types = match input.is_empty() || peek_id(input) {
+            true    => None,
+            false   => {
+                input.parse::<Token![,]>()?;
+                let arg = input.parse()?;
+                input.parse::<Token![->]>()?;
+                Some((arg, input.parse()?))
+            }
+        };
+
+        Ok(RequestArgs { name, types, id : parse_id(input)? })
+    }
+}
@@ -1,17 +1,51 @@
 pub mod authentic_execution {
-    extern crate base64;
     extern crate reactive_crypto;
     extern crate reactive_net;
+    extern crate sgx_attestation;
 
     use std::collections::{HashMap, HashSet};
-    use std::sync::Mutex;
-    use std::net::TcpStream;
+    use std::sync::{Arc, Mutex, RwLock};
+    use std::sync::mpsc::{sync_channel, SyncSender, Receiver, TrySendError, RecvTimeoutError};
 
     use reactive_net::{ResultCode, CommandCode, ResultMessage, CommandMessage, EntrypointID};
     use reactive_crypto::Encryption;
     use crate::__run::MODULE_KEY;
-    #[cfg(feature = "measure_time")]
-    use std::time::{SystemTime, UNIX_EPOCH};
+    use crate::__cipher::Cipher;
+    use crate::__net::{Address, Stream, host_from_env, port_from_env};
+    use std::time::{SystemTime, UNIX_EPOCH, Duration, Instant};
+    use hkdf::Hkdf;
+    use hmac::{Hmac, Mac, NewMac};
+    use sha2::Sha256;
+    use zeroize::Zeroizing;
+    use serde::Serialize;
+    use serde::de::DeserializeOwned;
+
+    /// Length of the challenge sent by the deployer to the `attest` entry point
+    const CHALLENGE_LENGTH : usize = 16;
+
+    /// Labels of the messages authenticated with the attestation key (see `attestation_tag`)
+    const ATTEST_LABEL : &[u8] = b"attest\0";
+    const GET_NONCE_LABEL : &[u8] = b"get_nonce\0";
+
+    /// Maximum size of the replay window of a connection (see `set_key`)
+    const MAX_REPLAY_WINDOW : u8 = 64;
+
+    /// Result returned by `handle_input` and `handle_handler` when the nonce space of the
+    /// connection is exhausted, i.e., the connection must be rekeyed, and by the management
+    /// commands when the management nonce is exhausted. `ResultCode` has no dedicated
+    /// variant: the code is `NONCE_EXHAUSTED`, with `NONCE_EXHAUSTED_PAYLOAD` as payload
+    pub const NONCE_EXHAUSTED : ResultCode = ResultCode::GenericError;
+    pub const NONCE_EXHAUSTED_PAYLOAD : &[u8] = b"nonce exhausted";
+
+    /// Number of nonces of a connection reserved at a time by the persisted state (see `reserve_nonces`)
+    const NONCE_BLOCK : u64 = 1024;
+
+    /// Maximum number of outputs waiting to be dispatched to the Event Manager
+    const OUTPUT_QUEUE_SIZE : usize = 256;
+
+    /// Maximum number of idle connections to the Event Manager kept open for reuse,
+    /// for outputs and for requests. Not used in SGX enclaves (see `release_em_stream`)
+    const EM_POOL_SIZE : usize = 4;
 
     #[derive(Debug)]
     pub enum Error {
@@ -20,7 +54,21 @@ pub mod authentic_execution {
         CryptoError,
         NetworkError,
         PayloadTooLarge,
-        BadResponse
+        BadResponse,
+        // the request was rejected before reaching the handler (e.g., by the Event
+        // Manager, or because the request could not be authenticated)
+        #[allow(dead_code)] // the code is only read by `Display`
+        RemoteError(ResultCode),
+        // the handler failed, returning this code
+        #[allow(dead_code)]
+        HandlerError(ResultCode),
+        // no response was received in time. The request might have been executed
+        Timeout,
+        NonceExhausted,
+        PersistenceError,
+        OutputQueueFull,
+        #[allow(dead_code)]
+        SerializationError
     }
 
     impl std::fmt::Display for Error {
@@ -48,44 +96,222 @@ pub mod authentic_execution {
         }
     }
 
+    /// Persisted state: management nonce and serialized connections, with the version of
+    /// the last snapshot written. Each connection is serialized when it changes, so that
+    /// a snapshot can be written without locking the other connections
+    #[derive(Default)]
+    struct Snapshot {
+        version : u64,
+        nonce : u64,
+        connections : HashMap<u16, Zeroizing<Vec<u8>>>
+    }
+
+    impl Snapshot {
+        /// Serialize the state as [management_nonce - num_connections - (conn_id - connection)*].
+        /// The state contains the keys of the connections, and it is wiped on drop
+        fn serialize(&self) -> Zeroizing<Vec<u8>> {
+            let len = 10 + self.connections.values().map(|conn| 2 + conn.len()).sum::<usize>();
+            let mut data = Zeroizing::new(Vec::with_capacity(len));
+            data.extend_from_slice(&u64_to_data(self.nonce));
+            data.extend_from_slice(&u16_to_data(self.connections.len() as u16));
+
+            for (conn_id, conn) in self.connections.iter() {
+                data.extend_from_slice(&u16_to_data(*conn_id));
+                data.extend_from_slice(conn);
+            }
+
+            data
+        }
+    }
+
     mod connection {
+        use std::sync::Arc;
         use reactive_crypto::Encryption;
+        use zeroize::Zeroizing;
+        use crate::__cipher::Cipher;
+        use super::Error;
 
         pub struct Connection {
             index : u16,
-            nonce : u16,
-            key : Vec<u8>,
-            encryption : Encryption
+            nonce : u64,
+            epoch : u32,
+            key : Zeroizing<Vec<u8>>,
+            encryption : Encryption,
+            // prepared from `key` and `encryption`, shared with the events being processed
+            cipher : Arc<Cipher>,
+            // 0: strict ordering. Otherwise, size of the replay window
+            window : u8,
+            // windowed mode: bit `i` is set if nonce `nonce - 1 - i` has been received
+            received : u64,
+            // high-water mark of the persisted nonces (see `reserve_nonces`)
+            reserved : u64
         }
 
         impl Connection {
-            pub fn new(index : u16, nonce : u16, key : Vec<u8>, encryption : Encryption) -> Connection {
-                Connection {
+            /// Returns `None` if `key` is not a valid key for `encryption`
+            pub fn new(index : u16, nonce : u64, key : Zeroizing<Vec<u8>>, encryption : Encryption, window : u8) -> Option<Connection> {
+                let cipher = Arc::new(Cipher::new(&key, &encryption)?);
+
+                Some(Connection {
                     index,
                     nonce,
+                    epoch : 0,
                     key,
-                    encryption
-                }
+                    encryption,
+                    cipher,
+                    window,
+                    received : 0,
+                    reserved : 0
+                })
             }
 
             pub fn get_index(&self) -> u16 {
                 self.index
             }
 
-            pub fn get_nonce(&self) -> u16 {
+            pub fn get_nonce(&self) -> u64 {
                 self.nonce
             }
 
+            /// Check that the next `count` nonces can be used without exhausting the
+            /// nonce space of the connection. If not, the connection must be rekeyed
+            pub fn check_nonces(&self, count : u64) -> Result<(), Error> {
+                match self.nonce.checked_add(count) {
+                    Some(_) => Ok(()),
+                    None    => Err(Error::NonceExhausted)
+                }
+            }
+
+            pub fn get_reserved(&self) -> u64 {
+                self.reserved
+            }
+
+            pub fn set_reserved(&mut self, reserved : u64) {
+                self.reserved = reserved;
+            }
+
+            /// Callers must ensure that the nonce space is not exhausted by calling `check_nonces` first
             pub fn increment_nonce(&mut self) {
                 self.nonce += 1;
             }
 
-            pub fn get_key(&self) -> Vec<u8> {
-                self.key.clone()
+            /// In windowed mode, the nonce is explicitly sent along with each message
+            pub fn is_windowed(&self) -> bool {
+                self.window > 0
             }
 
-            pub fn get_encryption(&self) -> Encryption {
-                self.encryption.clone()
+            /// Check that a message encrypted with `nonce` can be accepted. In strict mode, `nonce`
+            /// must be the next nonce. In windowed mode, `nonce` must not have been received yet
+            /// and must not be older than the window
+            pub fn check_received(&self, nonce : u64) -> Result<(), Error> {
+                if nonce.checked_add(1).is_none() {
+                    return Err(Error::NonceExhausted)
+                }
+
+                if !self.is_windowed() {
+                    return match nonce == self.nonce {
+                        true    => Ok(()),
+                        false   => Err(Error::CryptoError)
+                    }
+                }
+
+                if nonce >= self.nonce {
+                    return Ok(())
+                }
+
+                let age = self.nonce - 1 - nonce;
+                if age >= self.window as u64 || self.received & (1 << age) != 0 {
+                    return Err(Error::CryptoError)
+                }
+
+                Ok(())
+            }
+
+            /// Mark `nonce` as received. To be called only after the message has been authenticated
+            pub fn set_received(&mut self, nonce : u64) {
+                if nonce < self.nonce {
+                    self.received |= 1 << (self.nonce - 1 - nonce);
+                    return
+                }
+
+                let shift = nonce - self.nonce + 1;
+                self.received = match shift {
+                    s if s < 64     => self.received << s,
+                    _               => 0
+                } | 1;
+                self.nonce = nonce + 1;
+            }
+
+            pub fn get_epoch(&self) -> u32 {
+                self.epoch
+            }
+
+            /// Replace the key of the connection, moving to the next epoch. The nonce is reset.
+            /// Events still being processed keep using the cipher of the previous epoch
+            pub fn rekey(&mut self, key : Zeroizing<Vec<u8>>, epoch : u32) -> Result<(), Error> {
+                self.cipher = match Cipher::new(&key, &self.encryption) {
+                    Some(c) => Arc::new(c),
+                    None    => return Err(Error::CryptoError)
+                };
+                self.key = key;
+                self.epoch = epoch;
+                self.nonce = 0;
+                self.received = 0;
+                self.reserved = 0;
+                Ok(())
+            }
+
+            /// The cipher can be cloned cheaply to use it after releasing the connection
+            pub fn get_cipher(&self) -> &Arc<Cipher> {
+                &self.cipher
+            }
+
+            /// Serialize the connection as
+            /// [index - encryption - window - epoch - reserved - key_len - key]
+            /// The nonce is not saved: the connection is restored at its high-water mark.
+            /// The buffer is allocated once with its final size, so that no copies of the key are left
+            pub fn serialize(&self) -> Zeroizing<Vec<u8>> {
+                let enc : u8 = match self.encryption {
+                    Encryption::Aes         => 0,
+                    Encryption::Spongent    => 1
+                };
+
+                let mut data = Zeroizing::new(Vec::with_capacity(18 + self.key.len()));
+
+                data.extend_from_slice(&self.index.to_be_bytes());
+                data.push(enc);
+                data.push(self.window);
+                data.extend_from_slice(&self.epoch.to_be_bytes());
+                data.extend_from_slice(&self.reserved.to_be_bytes());
+                data.extend_from_slice(&(self.key.len() as u16).to_be_bytes());
+                data.extend_from_slice(&self.key);
+                data
+            }
+
+            /// Deserialize a connection, advancing `data` past it. The nonces below the
+            /// high-water mark might have been used before: they are never used again, and
+            /// in windowed mode they are all considered as received
+            pub fn deserialize(data : &mut &[u8]) -> Option<Connection> {
+                let index = super::data_to_u16(super::take(data, 2)?);
+                let encryption = Encryption::from_u8(super::take(data, 1)?[0])?;
+                let window = super::take(data, 1)?[0];
+                let epoch = super::data_to_u32(super::take(data, 4)?);
+                let reserved = super::data_to_u64(super::take(data, 8)?);
+                let key_len = super::data_to_u16(super::take(data, 2)?) as usize;
+                let key = Zeroizing::new(super::take(data, key_len)?.to_vec());
+                let cipher = Arc::new(Cipher::new(&key, &encryption)?);
+
+                Some(Connection {
+                    index,
+                    nonce : reserved,
+                    epoch,
+                    key,
+                    encryption,
+                    cipher,
+                    window,
+                    received : u64::MAX,
+                    reserved
+                })
             }
         }
     }
@@ -100,11 +326,34 @@ pub mod authentic_execution {
         u32::from_be_bytes([data[0], data[1], data[2], data[3]])
     }
 
+    #[allow(dead_code)]
+    pub fn data_to_u64(data : &[u8]) -> u64 {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&data[..8]);
+        u64::from_be_bytes(bytes)
+    }
+
+    /// Split the first `len` bytes from `data`, if available
+    fn take<'a>(data : &mut &'a [u8], len : usize) -> Option<&'a [u8]> {
+        if data.len() < len {
+            return None
+        }
+
+        let (head, tail) = data.split_at(len);
+        *data = tail;
+        Some(head)
+    }
+
     #[allow(dead_code)]
     pub fn u16_to_data(val : u16) -> [u8; 2] {
         val.to_be_bytes()
     }
 
+    #[allow(dead_code)]
+    pub fn u64_to_data(val : u64) -> [u8; 8] {
+        val.to_be_bytes()
+    }
+
     pub fn success(data : Option<Vec<u8>>) -> ResultMessage {
         ResultMessage::new(ResultCode::Ok, data)
     }
@@ -113,11 +362,22 @@ pub mod authentic_execution {
         ResultMessage::new(code, data)
     }
 
+    fn nonce_exhausted() -> ResultMessage {
+        failure(NONCE_EXHAUSTED, Some(NONCE_EXHAUSTED_PAYLOAD.to_vec()))
+    }
+
+    /// Check if a result has been returned because of an exhausted nonce space (see `NONCE_EXHAUSTED`)
+    pub fn is_nonce_exhausted(result : &ResultMessage) -> bool {
+        *result.get_code() == NONCE_EXHAUSTED &&
+            result.get_payload().map(|p| &p[..]) == Some(NONCE_EXHAUSTED_PAYLOAD)
+    }
+
     #[cfg(feature = "debug_prints")]
     #[macro_export]
     macro_rules! debug {
-        ($msg:expr) => {{
-                println!("[{}] DEBUG: {}", &*MODULE_NAME, $msg);
+        ($($args:expr),*) => {{
+            print!("[{}] DEBUG: ", &*$crate::__authentic_execution::authentic_execution::MODULE_NAME);
+            println!($($args),*);
         }};
     }
     #[cfg(not(feature = "debug_prints"))]
@@ -127,33 +387,121 @@ pub mod authentic_execution {
     }
     #[macro_export]
     macro_rules! info {
-        ($msg:expr) => {{
-                println!("[{}] INFO: {}", &*MODULE_NAME, $msg);
+        ($($args:expr),*) => {{
+            print!("[{}] INFO: ", &*$crate::__authentic_execution::authentic_execution::MODULE_NAME);
+            println!($($args),*);
         }};
     }
     #[macro_export]
-    macro_rules! error {
-        ($msg:expr) => {{
-                eprintln!("[{}] ERROR: {}", &*MODULE_NAME, $msg);
+    macro_rules! warning {
+        ($($args:expr),*) => {{
+            print!("[{}] WARNING: ", &*$crate::__authentic_execution::authentic_execution::MODULE_NAME);
+            println!($($args),*);
         }};
     }
     #[macro_export]
-    macro_rules! warning {
-        ($msg:expr) => {{
-                eprintln!("[{}] WARNING: {}", &*MODULE_NAME, $msg);
+    macro_rules! error {
+        ($($args:expr),*) => {{
+            print!("[{}] ERROR: ", &*$crate::__authentic_execution::authentic_execution::MODULE_NAME);
+            println!($($args),*);
         }};
     }
 
-    #[cfg(feature = "measure_time")]
-    pub fn measure_time(msg : &str) {
+    #[allow(dead_code)]
+    pub fn measure_time_ms(msg : &str) {
+        match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(d)   => info!("{}: {} ms", msg, d.as_millis()),
+            Err(_)  => info!("{}: ERROR", msg)
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn measure_time_us(msg : &str) {
         match SystemTime::now().duration_since(UNIX_EPOCH) {
-            Ok(d)   => info!(&format!("{}: {} us", msg, d.as_micros())),
-            Err(_)  => info!(&format!("{}: ERROR", msg))
+            Ok(d)   => info!("{}: {} us", msg, d.as_micros()),
+            Err(_)  => info!("{}: ERROR", msg)
         }
     }
 
+    #[cfg(feature = "measure_time")]
+    fn _measure_time(msg : &str) {
+        measure_time_us(msg);
+    }
+
     #[cfg(not(feature = "measure_time"))]
-    pub fn measure_time(_msg : &str) {}
+    fn _measure_time(_msg : &str) {}
+
+    /// Serialize a value of a typed output, request, entry point or handler
+    #[allow(dead_code)]
+    pub fn encode<T : Serialize + ?Sized>(value : &T) -> Result<Vec<u8>, Error> {
+        bincode::serialize(value).map_err(|_| Error::SerializationError)
+    }
+
+    /// Deserialize a value of a typed input, entry point, handler or request
+    #[allow(dead_code)]
+    pub fn decode<T : DeserializeOwned>(data : &[u8]) -> Result<T, Error> {
+        bincode::deserialize(data).map_err(|_| Error::SerializationError)
+    }
+
+    type InputFn = fn(&[u8]) -> Result<(), ResultCode>;
+    type EntryFn = fn(&[u8]) -> ResultMessage;
+    type HandlerFn = fn(&[u8]) -> Result<Vec<u8>, ResultCode>;
+
+    #[allow(dead_code)]
+    fn raw_input(input : fn(&[u8]), data : &[u8]) -> Result<(), ResultCode> {
+        input(data);
+        Ok(())
+    }
+
+    #[allow(dead_code)]
+    fn typed_input<T : DeserializeOwned>(input : fn(T), data : &[u8]) -> Result<(), ResultCode> {
+        let arg = decode(data).map_err(|_| ResultCode::IllegalPayload)?;
+        input(arg);
+        Ok(())
+    }
+
+    #[allow(dead_code)]
+    fn typed_entry<T : DeserializeOwned, R : Serialize>(entry : fn(T) -> R, data : &[u8]) -> ResultMessage {
+        let arg = match decode(data) {
+            Ok(a)   => a,
+            Err(_)  => return failure(ResultCode::IllegalPayload, None)
+        };
+
+        match encode(&entry(arg)) {
+            Ok(d)   => success(Some(d)),
+            Err(e)  => {
+                error!("{}", e);
+                failure(ResultCode::InternalError, None)
+            }
+        }
+    }
+
+    #[allow(dead_code)]
+    fn raw_handler(handler : fn(&[u8]) -> Vec<u8>, data : &[u8]) -> Result<Vec<u8>, ResultCode> {
+        Ok(handler(data))
+    }
+
+    #[allow(dead_code)]
+    fn typed_handler<T : DeserializeOwned, R : Serialize>(handler : fn(T) -> R, data : &[u8])
+        -> Result<Vec<u8>, ResultCode> {
+        let arg = decode(data).map_err(|_| ResultCode::IllegalPayload)?;
+
+        encode(&handler(arg)).map_err(|e| {
+            error!("{}", e);
+            ResultCode::InternalError
+        })
+    }
+
+    #[allow(dead_code)]
+    fn typed_fallible_handler<T : DeserializeOwned, R : Serialize>(handler : fn(T) -> Result<R, ResultCode>,
+        data : &[u8]) -> Result<Vec<u8>, ResultCode> {
+        let arg = decode(data).map_err(|_| ResultCode::IllegalPayload)?;
+
+        encode(&handler(arg)?).map_err(|e| {
+            error!("{}", e);
+            ResultCode::InternalError
+        })
+    }
 
     /// This is the only interface to the software module from outside
     /// Each request has to be sent to this function
@@ -176,77 +524,213 @@ pub mod authentic_execution {
     }
 
     pub fn set_key_wrapper(data : &[u8]) -> ResultMessage  {
-        // The payload is: [encryption_type - index - nonce - cipher]
+        // The payload is: [encryption_type - conn_id - index - window - cipher]
         debug!("ENTRYPOINT: set_key");
 
-        if data.len() < 7 {
+        if data.len() < 6 {
             return failure(ResultCode::IllegalPayload, None)
         }
 
-        set_key(data[0], &data[1..3], &data[3..5], &data[5..7], &data[7..])
+        set_key(data[0], &data[1..3], &data[3..5], data[5], &data[6..])
     }
 
-    fn set_key(enc : u8, conn_id : &[u8], index : &[u8], nonce : &[u8], cipher : &[u8]) -> ResultMessage {
+    fn set_key(enc : u8, conn_id : &[u8], index : &[u8], window : u8, cipher : &[u8]) -> ResultMessage {
         // The tag is included in the cipher
+        // The associated data is [encryption_type - conn_id - index - window - management_nonce]
+        // window is 0 for strict ordering, otherwise the size of the replay window (inputs and outputs only)
 
-        let nonce_u16 = data_to_u16(nonce);
-        if !check_nonce(nonce_u16) {
+        let index_u16 = data_to_u16(index);
+
+        if window > MAX_REPLAY_WINDOW {
             return failure(ResultCode::IllegalPayload, None)
         }
 
+        match IndexType::from_u16(index_u16) {
+            IndexType::Request | IndexType::Handler if window > 0 => {
+                return failure(ResultCode::IllegalPayload, None)
+            },
+            _ => {}
+        }
+
         let mut ad = vec!(enc);
         ad.extend_from_slice(conn_id);
         ad.extend_from_slice(index);
-        //TODO do not trust this nonce but keep an internal one
-        ad.extend_from_slice(nonce);
+        ad.push(window);
 
-        let decoded_key = match base64::decode(&*MODULE_KEY) {
-            Ok(k)   => k,
-            Err(_)  => return failure(ResultCode::InternalError, None)
-        };
-
-        let key = match reactive_crypto::decrypt(cipher, &decoded_key, &ad, &Encryption::Aes) {
+        let key = match decrypt_management(cipher, &ad) {
            Ok(k)    => k,
-           Err(_)   => return failure(ResultCode::CryptoError, None)
+           Err(r)   => return r
         };
 
-        increment_nonce();
-
         let enc_type = match Encryption::from_u8(enc) {
             Some(e) => e,
             None    => return failure(ResultCode::CryptoError, None)
         };
 
-        let index_u16 = data_to_u16(index);
         let conn_id_u16 = data_to_u16(conn_id);
-        let conn = connection::Connection::new(index_u16, 0, key, enc_type);
-        add_connection(conn_id_u16, conn);
+        let conn = match connection::Connection::new(index_u16, 0, key, enc_type, window) {
+            Some(c) => c,
+            None    => return failure(ResultCode::CryptoError, None)
+        };
+        let conn_ref = register_connection(conn_id_u16, conn);
 
-        // if index is an output, add to "outputs"
-        // if index is request, add to "requests"
-        match IndexType::from_u16(index_u16) {
-            IndexType::Output   => {
-                add_output(index_u16, conn_id_u16);
-            },
-            IndexType::Request  => {
-                add_request(index_u16, conn_id_u16);
-            },
-            _                   => {}
-        }
+        // the connection is serialized while locked, so that a newer reservation is not overwritten
+        let conn = conn_ref.lock().unwrap();
+        persist_connections(|c| {
+            if is_registered(conn_id_u16, &conn_ref) {
+                c.insert(conn_id_u16, conn.serialize());
+            }
+        });
 
         success(None)
     }
 
-    pub fn attest_wrapper(_data : &[u8]) -> ResultMessage  {
-        // The payload is: <TODO>
+    pub fn attest_wrapper(data : &[u8]) -> ResultMessage  {
+        // The payload is: [challenge]
         debug!("ENTRYPOINT: attest");
 
-        error!("attest entrypoint not implemented!");
-        failure(ResultCode::BadRequest, None)
+        if data.len() != CHALLENGE_LENGTH {
+            return failure(ResultCode::IllegalPayload, None)
+        }
+
+        attest(data)
+    }
+
+    fn attest(challenge : &[u8]) -> ResultMessage {
+        // The response is the attestation tag over [challenge - module_id - module_name]
+
+        let mut message = challenge.to_vec();
+        message.extend_from_slice(&u16_to_data(*MODULE_ID));
+        message.extend_from_slice(MODULE_NAME.as_bytes());
+
+        match attestation_tag(ATTEST_LABEL, &message) {
+            Ok(tag) => success(Some(tag)),
+            Err(_)  => failure(ResultCode::InternalError, None)
+        }
+    }
+
+    pub fn get_nonce_wrapper(data : &[u8]) -> ResultMessage  {
+        // The payload is: [challenge]
+        debug!("ENTRYPOINT: get_nonce");
+
+        if data.len() != CHALLENGE_LENGTH {
+            return failure(ResultCode::IllegalPayload, None)
+        }
+
+        get_nonce(data)
+    }
+
+    fn get_nonce(challenge : &[u8]) -> ResultMessage {
+        // Allows the deployer to resynchronize with the internal management nonce.
+        // The response is [management_nonce - state_version - tag], where the tag is the
+        // attestation tag over [challenge - management_nonce - state_version], and state_version
+        // is the version of the last snapshot of the persisted state (0 without persistence)
+
+        let mut response = u64_to_data(*NONCE.lock().unwrap()).to_vec();
+        response.extend_from_slice(&u64_to_data(SNAPSHOT.lock().unwrap().version));
+
+        let mut message = challenge.to_vec();
+        message.extend_from_slice(&response);
+
+        let tag = match attestation_tag(GET_NONCE_LABEL, &message) {
+            Ok(t)   => t,
+            Err(_)  => return failure(ResultCode::InternalError, None)
+        };
+
+        response.extend_from_slice(&tag);
+
+        success(Some(response))
+    }
+
+    pub fn rekey_wrapper(data : &[u8]) -> ResultMessage  {
+        // The payload is: [conn_id - cipher]
+        debug!("ENTRYPOINT: rekey");
+
+        if data.len() < 2 {
+            return failure(ResultCode::IllegalPayload, None)
+        }
+
+        rekey(&data[0..2], &data[2..])
+    }
+
+    fn rekey(conn_id : &[u8], cipher : &[u8]) -> ResultMessage {
+        // The tag is included in the cipher
+        // The associated data is [conn_id - epoch - management_nonce], where epoch is the new epoch
+        // of the connection. Index, encryption type and output/request registration are preserved
+
+        let conn_id_u16 = data_to_u16(conn_id);
+
+        let conn_ref = match get_connection(conn_id_u16) {
+            Some(c) => c,
+            None    => return failure(ResultCode::BadRequest, None)
+        };
+
+        // keep the lock until the connection is updated, to avoid events being
+        // processed with an inconsistent state
+        let mut conn = conn_ref.lock().unwrap();
+
+        let epoch = match conn.get_epoch().checked_add(1) {
+            Some(e) => e,
+            None    => return failure(ResultCode::InternalError, None)
+        };
+
+        let mut ad = conn_id.to_vec();
+        ad.extend_from_slice(&epoch.to_be_bytes());
+
+        let key = match decrypt_management(cipher, &ad) {
+           Ok(k)    => k,
+           Err(r)   => return r
+        };
+
+        if let Err(e) = conn.rekey(key, epoch) {
+            error!("{}", e);
+            return failure(ResultCode::CryptoError, None)
+        }
+
+        persist_connections(|c| {
+            if is_registered(conn_id_u16, &conn_ref) {
+                c.insert(conn_id_u16, conn.serialize());
+            }
+        });
+
+        success(None)
+    }
+
+    pub fn remove_connection_wrapper(data : &[u8]) -> ResultMessage  {
+        // The payload is: [conn_id - cipher]
+        debug!("ENTRYPOINT: remove_connection");
+
+        if data.len() < 2 {
+            return failure(ResultCode::IllegalPayload, None)
+        }
+
+        remove_connection(&data[0..2], &data[2..])
+    }
+
+    fn remove_connection(conn_id : &[u8], cipher : &[u8]) -> ResultMessage {
+        // The tag is included in the cipher
+        // The associated data is [conn_id - management_nonce]
+
+        if let Err(r) = decrypt_management(cipher, conn_id) {
+            return r
+        };
+
+        let conn_id_u16 = data_to_u16(conn_id);
+
+        if !delete_connection(conn_id_u16) {
+            return failure(ResultCode::BadRequest, None) // the connection does not exist
+        }
+
+        persist_connections(|c| {
+            c.remove(&conn_id_u16);
+        });
+
+        success(None)
     }
 
     pub fn handle_input_wrapper(data : &[u8]) -> ResultMessage  {
         // The payload is: [index - payload]
+        // In windowed mode, payload is: [nonce - cipher]
         debug!("ENTRYPOINT: handle_input");
 
         if data.len() < 2 {
@@ -259,34 +743,61 @@ pub mod authentic_execution {
     fn handle_input(conn_id : u16, payload : &[u8]) -> ResultMessage {
         // the index is not associated data because it is not sent by the `from` module, but by the event manager
 
-        let mut map = CONNECTIONS.lock().unwrap();
-        let conn = match map.get_mut(&conn_id) {
+        let conn_ref = match get_connection(conn_id) {
             Some(v) => v,
             None => return failure(ResultCode::BadRequest, None)
         };
 
-        measure_time("handle_input_before_decryption");
+        // only this connection is locked: events of other connections are processed in parallel
+        let mut conn = conn_ref.lock().unwrap();
 
-        let nonce = conn.get_nonce();
-        let data = match reactive_crypto::decrypt(payload, &conn.get_key(), &u16_to_data(nonce), &conn.get_encryption()) {
+        let (nonce, payload) = match conn.is_windowed() {
+            true if payload.len() < 8   => return failure(ResultCode::IllegalPayload, None),
+            true                        => (data_to_u64(payload), &payload[8..]),
+            false                       => (conn.get_nonce(), payload)
+        };
+
+        match conn.check_received(nonce) {
+            Ok(_)                       => (),
+            Err(Error::NonceExhausted)  => {
+                error!("{}", Error::NonceExhausted);
+                return nonce_exhausted()
+            },
+            Err(e)                      => {
+                error!("{}", e);
+                return failure(ResultCode::CryptoError, None)
+            }
+        }
+
+        _measure_time("handle_input_before_decryption");
+
+        let data = match conn.get_cipher().decrypt(payload, conn.get_epoch(), nonce) {
            Ok(d) => d,
            Err(_) => return failure(ResultCode::CryptoError, None)
         };
 
-        conn.increment_nonce();
+        // the nonce is saved before the message is accepted, so that it cannot be replayed
+        if let Err(e) = reserve_nonces(conn_id, &conn_ref, &mut conn, nonce + 1) {
+            error!("{}", e);
+            return failure(ResultCode::InternalError, None)
+        }
+
+        conn.set_received(nonce);
         let index = &conn.get_index();
-        drop(map); // release map as soon as we don't need it anymore
+        drop(conn); // release the connection as soon as we don't need it anymore
 
-        measure_time("handle_input_after_decryption");
+        _measure_time("handle_input_after_decryption");
 
         let handler = match INPUTS.get(index) {
             Some(h) => h,
             None => return failure(ResultCode::BadRequest, None)
         };
 
-        handler(&data);
+        if let Err(code) = handler(&data) {
+            return failure(code, None)
+        }
 
-        measure_time("handle_input_after_handler");
+        _measure_time("handle_input_after_handler");
 
         success(None)
     }
@@ -306,33 +817,45 @@ pub mod authentic_execution {
         // the index is not associated data because it is not sent by the `from` module, but by the event manager
 
         // get connection from map
-        let mut map = CONNECTIONS.lock().unwrap();
-        let conn = match map.get_mut(&conn_id) {
+        let conn_ref = match get_connection(conn_id) {
             Some(v) => v,
             None => return failure(ResultCode::BadRequest, None)
         };
 
-        measure_time("handle_handler_before_1st_decryption");
+        let mut conn = conn_ref.lock().unwrap();
+
+        // two nonces are needed: one for the request and one for the response
+        if let Err(e) = conn.check_nonces(2) {
+            error!("{}", e);
+            return nonce_exhausted()
+        }
+
+        _measure_time("handle_handler_before_1st_decryption");
 
         let nonce = conn.get_nonce();
-        let key = conn.get_key();
-        let encryption = conn.get_encryption();
+        let epoch = conn.get_epoch();
+        let cipher = conn.get_cipher().clone();
         let index = conn.get_index();
 
         // decrypt payload
-        let data = match reactive_crypto::decrypt(payload, &key, &u16_to_data(nonce), &encryption) {
+        let data = match cipher.decrypt(payload, epoch, nonce) {
            Ok(d) => d,
            Err(_) => return failure(ResultCode::CryptoError, None)
         };
 
+        if let Err(e) = reserve_nonces(conn_id, &conn_ref, &mut conn, nonce + 2) {
+            error!("{}", e);
+            return failure(ResultCode::InternalError, None)
+        }
+
         // increment nonce twice, also for next encryption (which always succeeds).
         conn.increment_nonce();
         conn.increment_nonce();
 
-        // release lock of map, so that it can be used by other threads
-        drop(map);
+        // release lock of the connection, so that it can be used by other threads
+        drop(conn);
 
-        measure_time("handle_handler_after_1st_decryption");
+        _measure_time("handle_handler_after_1st_decryption");
 
         // execute handler
         let handler = match HANDLERS.get(&index) {
@@ -340,102 +863,148 @@ pub mod authentic_execution {
             None => return failure(ResultCode::InternalError, None) // it should never happen
         };
 
-        let result = handler(&data);
+        // the response is [code - data]: the result of the handler is authenticated as well
+        let result = match handler(&data) {
+            Ok(r)       => {
+                let mut result = Vec::with_capacity(1 + r.len());
+                result.push(ResultCode::Ok as u8);
+                result.extend_from_slice(&r);
+                result
+            },
+            Err(code)   => vec!(code as u8)
+        };
 
-        measure_time("handle_handler_after_handler");
+        _measure_time("handle_handler_after_handler");
 
         // encrypt response
-        let response = match reactive_crypto::encrypt(&result, &key,
-                                        &u16_to_data(nonce+1), &encryption) {
-           Ok(p)    => p,
-           Err(_)   => return failure(ResultCode::CryptoError, None)
-        };
+        let mut response = Vec::new();
+        if cipher.encrypt(&result, epoch, nonce+1, &mut response).is_err() {
+            return failure(ResultCode::CryptoError, None)
+        }
 
-        measure_time("handle_handler_after_2nd_encryption");
+        _measure_time("handle_handler_after_2nd_encryption");
 
         success(Some(response))
     }
 
     pub fn disable_wrapper(data : &[u8]) -> ResultMessage  {
-        // The payload is: [nonce - cipher]
+        // The payload is: [cipher]
         debug!("ENTRYPOINT: disable");
 
-        if data.len() < 2 {
-            return failure(ResultCode::IllegalPayload, None)
-        }
-
-        disable(&data[0..2], &data[2..])
+        disable(data)
     }
 
-    fn disable(nonce : &[u8], cipher : &[u8]) -> ResultMessage {
+    fn disable(cipher : &[u8]) -> ResultMessage {
         // The tag is included in the cipher
+        // The associated data is [management_nonce]
 
-        let nonce_u16 = data_to_u16(nonce);
-        if !check_nonce(nonce_u16) {
-            return failure(ResultCode::IllegalPayload, None)
-        }
-
-        let decoded_key = match base64::decode(&*MODULE_KEY) {
-            Ok(k)   => k,
-            Err(_)  => return failure(ResultCode::InternalError, None)
+        if let Err(r) = decrypt_management(cipher, &[]) {
+            return r
         };
 
-        if let Err(_) = reactive_crypto::decrypt(cipher, &decoded_key, nonce, &Encryption::Aes) {
-            return failure(ResultCode::CryptoError, None)
-        };
-
-        increment_nonce();
-
         // delete all connections, making the module disabled in practice
         delete_all_connections();
 
+        persist_connections(|c| c.clear());
+
         success(None)
     }
 
+    /// Encrypt `data` and queue it for each connection of the output. If a connection
+    /// fails, the event is still queued for the others, and the first error is returned
     #[allow(dead_code)] // this is needed if we have no outputs to avoid warnings
-    pub fn handle_output(index : u16, data : &[u8]) {
+    pub fn handle_output(index : u16, data : &[u8]) -> Result<(), Error> {
         let connections = match get_connections_from_output(index) {
             Some(vec)       => vec,
-            None            => return // no connections associated to the output
+            None            => return Ok(()) // no connections associated to the output
         };
 
-        for conn_id in connections {
-            let mut map = CONNECTIONS.lock().unwrap();
+        let mut result = Ok(());
 
-            let conn = match map.get_mut(&conn_id) {
+        for conn_id in connections {
+            let conn_ref = match get_connection(conn_id) {
                 Some(c)     => c,
-                None        => {
-                    error!(&format!("{}", Error::InternalError));
-                    continue; // or break? Btw this SHOULD NEVER happen
-                }
+                None        => continue // the connection has been removed in the meantime
             };
 
-            measure_time("handle_output_before_encryption");
+            let mut conn = conn_ref.lock().unwrap();
+
+            if let Err(e) = conn.check_nonces(1) {
+                error!("{}", e);
+                result = result.and(Err(e));
+                continue; // refuse to encrypt, the connection must be rekeyed
+            }
+
+            _measure_time("handle_output_before_encryption");
 
             let nonce = conn.get_nonce();
-            let payload = match reactive_crypto::encrypt(data, &conn.get_key(),
-                                            &u16_to_data(nonce), &conn.get_encryption()) {
-               Ok(p) => p,
-               Err(e) => {
-                   error!(&format!("{}", e));
-                   return; //encryption failed (there's nothing we can do in this case)
-               }
+            if let Err(e) = reserve_nonces(conn_id, &conn_ref, &mut conn, nonce + 1) {
+                error!("{}", e);
+                result = result.and(Err(e));
+                continue;
+            }
+
+            // in windowed mode, the nonce is sent explicitly before the ciphertext
+            let mut payload = match conn.is_windowed() {
+                true    => u64_to_data(nonce).to_vec(),
+                false   => Vec::new()
             };
 
-            measure_time("handle_output_after_encryption");
+            if let Err(e) = conn.get_cipher().encrypt(data, conn.get_epoch(), nonce, &mut payload) {
+                error!("{}", e);
+                result = result.and(Err(Error::CryptoError));
+                continue; // encryption failed, the nonce is not consumed
+            }
+
+            _measure_time("handle_output_after_encryption");
+
+            // enqueue while holding the lock, so that events of the same connection
+            // are dispatched in nonce order. If the event cannot be enqueued, the nonce
+            // is not incremented and the connection stays in sync
+            if let Err(e) = enqueue_output(conn_id, payload) {
+                error!("{}", e);
+                result = result.and(Err(e));
+                continue;
+            }
 
             conn.increment_nonce();
-            let func = || drop(map);
-            if let Err(e) = send_to_em(EntrypointID::HandleInput as u16, conn_id, payload, false, func) {
-                error!(&format!("{}", e));
+            drop(conn);
+
+            _measure_time("handle_output_after_enqueue");
+        }
+
+        result
+    }
+
+    fn enqueue_output(conn_id : u16, payload : Vec<u8>) -> Result<(), Error> {
+        match OUTPUT_QUEUE.lock().unwrap().try_send((conn_id, payload)) {
+            Ok(_)                               => Ok(()),
+            Err(TrySendError::Full(_))          => Err(Error::OutputQueueFull),
+            Err(TrySendError::Disconnected(_))  => Err(Error::InternalError)
+        }
+    }
+
+    /// Deliver the queued outputs to the Event Manager, in order. Runs on a dedicated thread
+    fn dispatch_outputs(queue : Receiver<(u16, Vec<u8>)>) {
+        for (conn_id, payload) in queue {
+            if let Err(e) = send_to_em(EntrypointID::HandleInput as u16, conn_id, payload, false, None, || {}) {
+                error!("{}", e);
             }
 
-            measure_time("handle_output_after_dispatch");
+            _measure_time("handle_output_after_dispatch");
         }
     }
 
+    /// Send a request, waiting for the response at most for the default timeout (`REQUEST_TIMEOUT`)
     #[allow(dead_code)] // this is needed if we have no outputs to avoid warnings
     pub fn handle_request(index : u16, data : &[u8]) -> Result<Vec<u8>, Error> {
+        handle_request_with_timeout(index, data, *REQUEST_TIMEOUT)
+    }
+
+    /// Send a request, waiting for the response at most for `timeout` (`None`: no timeout)
+    #[allow(dead_code)]
+    pub fn handle_request_with_timeout(index : u16, data : &[u8], timeout : Option<Duration>)
+            -> Result<Vec<u8>, Error> {
         // find connection associated to the request
         let conn_id = match get_connection_from_request(index) {
             Some(c)     => c,
@@ -443,49 +1012,57 @@ pub mod authentic_execution {
         };
 
         // get connection from conn_id
-        let mut map = CONNECTIONS.lock().unwrap();
-        let conn = match map.get_mut(&conn_id) {
+        let conn_ref = match get_connection(conn_id) {
             Some(v)     => v,
             None        => return Err(Error::InternalError) // it shouldn't happen
         };
 
-        measure_time("handle_request_before_1st_encryption");
+        let mut conn = conn_ref.lock().unwrap();
+
+        // two nonces are needed: one for the request and one for the response
+        conn.check_nonces(2)?;
+
+        _measure_time("handle_request_before_1st_encryption");
 
         // encrypt payload
         let nonce = conn.get_nonce();
-        let key = conn.get_key();
-        let encryption = conn.get_encryption();
-
-        let payload = match reactive_crypto::encrypt(data, &key,
-                                        &u16_to_data(nonce), &encryption) {
-           Ok(p)    => p,
-           Err(_)   => return Err(Error::CryptoError)
-        };
+        reserve_nonces(conn_id, &conn_ref, &mut conn, nonce + 2)?;
+        let epoch = conn.get_epoch();
+        let cipher = conn.get_cipher().clone();
 
-        // increment nonce twice (also for decrypt later)
-        // if errors occur in the meantime, nonces between source and dest will be out of sync in any case.
-        // better increment them immediately
-        conn.increment_nonce();
-        conn.increment_nonce();
+        let mut payload = Vec::new();
+        if cipher.encrypt(data, epoch, nonce, &mut payload).is_err() {
+            return Err(Error::CryptoError)
+        }
 
-        measure_time("handle_request_after_1st_encryption");
+        _measure_time("handle_request_after_1st_encryption");
 
         // send payload:
-        // drop map only after the message is sent to the EM.
-        // to avoid out-of-order events in parallel executions of the same request
-        let func = || drop(map);
-        let response = match send_to_em(EntrypointID::HandleHandler as u16, conn_id, payload, true,
-            func)? {
+        // release the connection only after the message is sent to the EM.
+        // to avoid out-of-order events in parallel executions of the same request.
+        // The nonces (request and response) are consumed only once the request is sent: if
+        // it cannot be sent, the connection stays in sync. Once sent, they are never reused,
+        // even if the response does not arrive in time (the handler might have received it)
+        let func = move || {
+            conn.increment_nonce();
+            conn.increment_nonce();
+            drop(conn);
+        };
+        let response = send_to_em(EntrypointID::HandleHandler as u16, conn_id, payload, true, timeout, func);
+
+        let response = match response? {
             Some(r)     => r,
             None        => return Err(Error::InternalError) //it should never happen
         };
 
-        measure_time("handle_request_after_response_received");
+        _measure_time("handle_request_after_response_received");
 
         // Check response
+        // the connection of the handler might be exhausted as well
         let resp_body = match response.get_code() {
-            ResultCode::Ok      => response.get_payload(),
-            _                   => return Err(Error::BadResponse)
+            ResultCode::Ok                          => response.get_payload(),
+            _ if is_nonce_exhausted(&response)      => return Err(Error::NonceExhausted),
+            code                                    => return Err(Error::RemoteError(code.clone()))
         };
 
         let resp_body = match resp_body {
@@ -494,24 +1071,32 @@ pub mod authentic_execution {
         };
 
         // decrypt response
-        let data = match reactive_crypto::decrypt(resp_body, &key,
-                                        &u16_to_data(nonce+1), &encryption) {
+        let mut data = match cipher.decrypt(resp_body, epoch, nonce+1) {
            Ok(d)    => d,
            Err(_)   => return Err(Error::CryptoError)
         };
 
-        measure_time("handle_request_after_2nd_decryption");
+        _measure_time("handle_request_after_2nd_decryption");
 
-        Ok(data)
+        // The response is: [code - data]
+        match data.first().and_then(|c| ResultCode::from_u8(*c)) {
+            Some(ResultCode::Ok)    => {
+                data.remove(0);
+                Ok(data)
+            },
+            Some(code)              => Err(Error::HandlerError(code)),
+            None                    => Err(Error::BadResponse)
+        }
     }
 
     /// Send the output payload to the event manager, which will forward it to the handler connected to the `index` id
     /// Blocking: we will wait for a response
-    fn send_to_em(entry_id : u16, conn_id : u16, mut data : Vec<u8>, has_resp : bool, func : impl FnOnce())
-            -> Result<Option<ResultMessage>, Error> {
-        let addr = format!("127.0.0.1:{}", *EM_PORT);
-
-        debug!(&format!("Sending request with conn ID {} to EM", conn_id));
+    /// Connections to the EM are kept open and reused: the EM processes the commands of a
+    /// connection in order, and only sends a result for commands that expect one.
+    /// `timeout` is the maximum time to wait for the result (`None`: no timeout)
+    fn send_to_em(entry_id : u16, conn_id : u16, mut data : Vec<u8>, has_resp : bool,
+            timeout : Option<Duration>, func : impl FnOnce()) -> Result<Option<ResultMessage>, Error> {
+        debug!("Sending request with conn ID {} to EM", conn_id);
 
         // Create payload
         let data_len = data.len();
@@ -524,94 +1109,296 @@ pub mod authentic_execution {
         payload.extend_from_slice(&conn_id.to_be_bytes());
         payload.append(&mut data);
 
-        // Connect to the EM
-        let mut stream = match TcpStream::connect(addr) {
-            Ok(s)   => s,
-            Err(_)  => return Err(Error::NetworkError)
+        // Take a connection to the EM from the pool, or open a new one
+        let pooled = take_em_stream(has_resp);
+        let is_pooled = pooled.is_some();
+        let mut stream = match pooled {
+            Some(s) => s,
+            None    => connect_to_em()?
         };
 
         // Send command
         let cmd = CommandMessage::new(CommandCode::ModuleOutput, Some(payload));
 
-        if let Err(_) = reactive_net::write_command(&mut stream, &cmd) {
-            return Err(Error::NetworkError)
+        if reactive_net::write_command(&mut stream, &cmd).is_err() {
+            // a pooled connection might have been closed by the EM in the meantime: retry once
+            if !is_pooled {
+                return Err(Error::NetworkError)
+            }
+
+            stream = connect_to_em()?;
+            if reactive_net::write_command(&mut stream, &cmd).is_err() {
+                return Err(Error::NetworkError)
+            }
         }
 
-        // execute function (i.e., drop the lock on the connections map)
+        // execute function (i.e., release the lock on the connection)
         func();
 
         // If has_resp, wait for result. Otherwise return
+        // If reading fails, the stream is dropped: we cannot know if the command has been executed.
+        // This also discards a result that arrives after the timeout
+        let result = match has_resp {
+            true    => {
+                let (result, s) = read_result(stream, timeout)?;
+                stream = s;
+                Some(result)
+            },
+            false   => None
+        };
+
+        release_em_stream(stream, has_resp);
+        Ok(result)
+    }
+
+    /// Read the result of a command, waiting at most for `timeout` (`None`: no timeout).
+    /// The stream is given back with the result, so that it can be reused
+    fn read_result(mut stream : Stream, timeout : Option<Duration>) -> Result<(ResultMessage, Stream), Error> {
+        // a zero timeout is not accepted by the streams
+        let timeout = timeout.map(|t| t.max(Duration::from_millis(1)));
+
+        // read timeouts have no effect in SGX enclaves: the result is read by another thread
+        if let (Some(t), true) = (timeout, cfg!(target_env = "sgx")) {
+            return read_result_in_thread(stream, t)
+        }
+
+        if stream.set_read_timeout(timeout).is_err() {
+            debug!("Cannot set request timeout");
+        }
+
+        let start = Instant::now();
+
+        match reactive_net::read_result(&mut stream) {
+            Ok(r)                                                       => Ok((r, stream)),
+            Err(_) if timeout.is_some_and(|t| start.elapsed() >= t)     => Err(Error::Timeout),
+            Err(_)                                                      => Err(Error::NetworkError)
+        }
+    }
+
+    /// Read the result of a command on a separate thread, waiting for it at most for `timeout`.
+    /// After a timeout, the thread keeps waiting until the result arrives or the EM closes the
+    /// connection, and then drops the stream. The enclave has one thread for each thread of
+    /// the module for this purpose (see the generated `Cargo.toml`)
+    fn read_result_in_thread(stream : Stream, timeout : Duration) -> Result<(ResultMessage, Stream), Error> {
+        let (sender, receiver) = sync_channel(1);
+
+        let reader = std::thread::Builder::new().spawn(move || {
+            let mut stream = stream;
+            let result = reactive_net::read_result(&mut stream);
+            let _ = sender.send((result, stream)); // nobody is waiting anymore after a timeout
+        });
+
+        if let Err(e) = reader {
+            error!("Cannot wait for the result: {}", e);
+            return Err(Error::InternalError)
+        }
+
+        match receiver.recv_timeout(timeout) {
+            Ok((Ok(r), stream))                 => Ok((r, stream)),
+            Ok((Err(_), _))                     => Err(Error::NetworkError),
+            Err(RecvTimeoutError::Timeout)      => Err(Error::Timeout),
+            Err(RecvTimeoutError::Disconnected) => Err(Error::InternalError)
+        }
+    }
+
+    fn connect_to_em() -> Result<Stream, Error> {
+        match EM_ADDRESS.connect() {
+            Ok(s)   => Ok(s),
+            Err(_)  => Err(Error::NetworkError)
+        }
+    }
+
+    /// Idle connections to the EM. Outputs and requests never share a connection: no
+    /// result is read on the connections of the outputs, so a result sent by the EM
+    /// for an output could otherwise be taken as the response of a request
+    fn em_pool(has_resp : bool) -> &'static Mutex<Vec<Stream>> {
         match has_resp {
-            true    => match reactive_net::read_result(&mut stream) {
-                        Ok(r)   => Ok(Some(r)),
-                        Err(_)  => Err(Error::NetworkError)
-                        }
-            false   => Ok(None)
+            true    => &EM_REQUEST_STREAMS,
+            false   => &EM_OUTPUT_STREAMS
+        }
+    }
+
+    /// Take an idle connection to the EM from the pool. Connections closed by the EM, or
+    /// with unexpected data to read, are dropped: a write to a closed connection might
+    /// succeed, losing the command
+    fn take_em_stream(has_resp : bool) -> Option<Stream> {
+        let mut pool = em_pool(has_resp).lock().unwrap();
+
+        while let Some(mut stream) = pool.pop() {
+            if stream.is_idle() {
+                return Some(stream)
+            }
+
+            debug!("Dropping a closed connection to the EM");
+        }
+
+        None
+    }
+
+    /// Put a connection back to the pool, so that it can be reused by the next command.
+    /// In SGX enclaves, connections are never reused, as they cannot be checked (see `Stream::is_idle`)
+    fn release_em_stream(stream : Stream, has_resp : bool) {
+        let mut pool = em_pool(has_resp).lock().unwrap();
+
+        if pool.len() < EM_POOL_SIZE && !cfg!(target_env = "sgx") {
+            pool.push(stream);
         }
     }
 
     // Variables: connections. Contains, for each connection, key, nonce, and handler index
+    // The maps are only written when connections are added or removed. Each connection
+    // has its own lock, so that events of different connections are processed in parallel.
+    // Lock order: `CONNECTIONS`, then a connection
     lazy_static! {
-        static ref CONNECTIONS: Mutex<HashMap<u16, connection::Connection>> = {
-            Mutex::new(HashMap::new())
+        static ref CONNECTIONS: RwLock<HashMap<u16, Arc<Mutex<connection::Connection>>>> = {
+            RwLock::new(HashMap::new())
         };
-        static ref OUTPUTS: Mutex<HashMap<u16, HashSet<u16>>> = {
-            Mutex::new(HashMap::new())
+        static ref OUTPUTS: RwLock<HashMap<u16, HashSet<u16>>> = {
+            RwLock::new(HashMap::new())
         };
-        static ref REQUESTS: Mutex<HashMap<u16, u16>> = {
-            Mutex::new(HashMap::new())
+        static ref REQUESTS: RwLock<HashMap<u16, u16>> = {
+            RwLock::new(HashMap::new())
         };
-        static ref NONCE: Mutex<u16> = {
+        static ref NONCE: Mutex<u64> = {
             Mutex::new(0)
         };
+        static ref SNAPSHOT: Mutex<Snapshot> = {
+            Mutex::new(Snapshot::default())
+        };
+        static ref EM_OUTPUT_STREAMS: Mutex<Vec<Stream>> = {
+            Mutex::new(Vec::with_capacity(EM_POOL_SIZE))
+        };
+        static ref EM_REQUEST_STREAMS: Mutex<Vec<Stream>> = {
+            Mutex::new(Vec::with_capacity(EM_POOL_SIZE))
+        };
+        static ref OUTPUT_QUEUE: Mutex<SyncSender<(u16, Vec<u8>)>> = {
+            let (sender, receiver) = sync_channel(OUTPUT_QUEUE_SIZE);
+            std::thread::spawn(move || dispatch_outputs(receiver));
+            Mutex::new(sender)
+        };
     }
 
     // Constants: Module's key, ID, Inputs, Outputs
     lazy_static! {
         pub static ref MODULE_ID: u16 = 1;
         pub static ref MODULE_NAME: &'static str = "input";
-        pub static ref EM_PORT: u16 = 5000;
-        pub static ref NUM_THREADS: usize = 1; //TODO assign custom value during code generation
-        static ref INPUTS: std::collections::HashMap<u16, fn(&[u8])> = {
+        pub static ref EM_PORT: u16 = port_from_env("EM_PORT", 5000);
+        pub static ref EM_ADDRESS: Address = Address::new(&host_from_env("EM_HOST", "127.0.0.1"), *EM_PORT);
+        pub static ref MODULE_PORT: u16 = port_from_env("MODULE_PORT", 5001);
+        pub static ref ATTESTATION_PORT: u16 = 5001;
+        pub static ref MODULE_ADDRESS: Address = Address::new(&host_from_env("MODULE_HOST", "127.0.0.1"),
+            *MODULE_PORT);
+        pub static ref NUM_THREADS: usize = 1;
+        pub static ref PERSIST_FILE: Option<&'static str> = None;
+        pub static ref REQUEST_TIMEOUT: Option<Duration> = Some(Duration::from_millis(10000));
+        static ref INPUTS: std::collections::HashMap<u16, InputFn> = {
             #[allow(unused_mut)]
             let mut m = std::collections::HashMap::new();
-    		m.insert(0, crate::input1 as fn(&[u8]));
+    		m.insert(0, (|d : &[u8]| raw_input(crate::input1, d)) as InputFn);
+		m.insert(1, (|d : &[u8]| typed_input(crate::sensors::new_reading, d)) as InputFn);
 
             m
         };
-        static ref ENTRYPOINTS: std::collections::HashMap<u16, fn(&[u8]) -> ResultMessage> = {
+        static ref ENTRYPOINTS: std::collections::HashMap<u16, EntryFn> = {
             let mut m = std::collections::HashMap::new();
-            m.insert(0, set_key_wrapper as fn(&[u8]) -> ResultMessage);
-            m.insert(1, attest_wrapper as fn(&[u8]) -> ResultMessage);
-            m.insert(2, disable_wrapper as fn(&[u8]) -> ResultMessage);
-            m.insert(3, handle_input_wrapper as fn(&[u8]) -> ResultMessage);
-            m.insert(4, handle_handler_wrapper as fn(&[u8]) -> ResultMessage);
-    		m.insert(5, crate::press_button as fn(&[u8]) -> ResultMessage);
+            m.insert(0, set_key_wrapper as EntryFn);
+            m.insert(1, attest_wrapper as EntryFn);
+            m.insert(2, disable_wrapper as EntryFn);
+            m.insert(3, handle_input_wrapper as EntryFn);
+            m.insert(4, handle_handler_wrapper as EntryFn);
+            m.insert(16381, remove_connection_wrapper as EntryFn);
+            m.insert(16382, rekey_wrapper as EntryFn);
+            m.insert(16383, get_nonce_wrapper as EntryFn);
+    		m.insert(5, crate::press_button as EntryFn);
+		m.insert(6, (|d : &[u8]| typed_entry(crate::sensors::num_readings, d)) as EntryFn);
 
             m
         };
-        static ref HANDLERS: std::collections::HashMap<u16, fn(&[u8]) -> Vec<u8>> = {
+        static ref HANDLERS: std::collections::HashMap<u16, HandlerFn> = {
             #[allow(unused_mut)]
             let mut m = std::collections::HashMap::new();
-    		m.insert(49152, crate::handler_value as fn(&[u8]) -> Vec<u8>);
+    		m.insert(49152, (|d : &[u8]| raw_handler(crate::handler_value, d)) as HandlerFn);
+		m.insert(49153, (|d : &[u8]| typed_fallible_handler(crate::sensors::last_reading, d)) as HandlerFn);
 
             m
         };
     }
 
 
-    fn add_connection(conn_id : u16, conn : connection::Connection) {
-        CONNECTIONS.lock().unwrap().insert(conn_id, conn);
+    fn add_connection(conn_id : u16, conn : connection::Connection) -> Arc<Mutex<connection::Connection>> {
+        let conn_ref = Arc::new(Mutex::new(conn));
+        CONNECTIONS.write().unwrap().insert(conn_id, conn_ref.clone());
+        conn_ref
+    }
+
+    /// Get a connection. The map is not locked while the connection is used: if the
+    /// connection is removed in the meantime, the ongoing operation still completes
+    fn get_connection(conn_id : u16) -> Option<Arc<Mutex<connection::Connection>>> {
+        CONNECTIONS.read().unwrap().get(&conn_id).cloned()
+    }
+
+    /// Check that `conn_ref` is the connection currently registered as `conn_id`. A connection
+    /// replaced by `set_key` or deleted in the meantime must not be saved again
+    fn is_registered(conn_id : u16, conn_ref : &Arc<Mutex<connection::Connection>>) -> bool {
+        match get_connection(conn_id) {
+            Some(c) => Arc::ptr_eq(&c, conn_ref),
+            None    => false
+        }
+    }
+
+    /// Add a connection, and register it to its output or request (if any)
+    fn register_connection(conn_id : u16, conn : connection::Connection) -> Arc<Mutex<connection::Connection>> {
+        let index = conn.get_index();
+        let conn_ref = add_connection(conn_id, conn);
+
+        // if index is an output, add to "outputs"
+        // if index is request, add to "requests"
+        match IndexType::from_u16(index) {
+            IndexType::Output   => {
+                add_output(index, conn_id);
+            },
+            IndexType::Request  => {
+                add_request(index, conn_id);
+            },
+            _                   => {}
+        }
+
+        conn_ref
+    }
+
+    /// Delete a single connection, also removing it from `OUTPUTS` or `REQUESTS`.
+    /// Returns false if the connection does not exist
+    fn delete_connection(conn_id : u16) -> bool {
+        // release the map before waiting for the connection, which might be locked
+        // during a round trip to the EM (e.g., by a request)
+        let conn = match CONNECTIONS.write().unwrap().remove(&conn_id) {
+            Some(c) => c,
+            None    => return false
+        };
+
+        let index = conn.lock().unwrap().get_index();
+
+        match IndexType::from_u16(index) {
+            IndexType::Output   => {
+                remove_output(index, conn_id);
+            },
+            IndexType::Request  => {
+                remove_request(index, conn_id);
+            },
+            _                   => {}
+        }
+
+        true
     }
 
     fn delete_all_connections() {
-        CONNECTIONS.lock().unwrap().clear();
-        OUTPUTS.lock().unwrap().clear();
-        REQUESTS.lock().unwrap().clear();
+        CONNECTIONS.write().unwrap().clear();
+        OUTPUTS.write().unwrap().clear();
+        REQUESTS.write().unwrap().clear();
     }
 
     fn add_output(out_id : u16, conn_id : u16) {
-        let mut map = OUTPUTS.lock().unwrap();
+        let mut map = OUTPUTS.write().unwrap();
 
         match map.get_mut(&out_id) {
             Some(set)   => {
@@ -625,30 +1412,238 @@ pub mod authentic_execution {
         }
     }
 
+    fn remove_output(out_id : u16, conn_id : u16) {
+        let mut map = OUTPUTS.write().unwrap();
+
+        if let Some(set) = map.get_mut(&out_id) {
+            set.remove(&conn_id);
+
+            if set.is_empty() {
+                map.remove(&out_id);
+            }
+        }
+    }
+
     fn get_connections_from_output(out_id : u16) -> Option<HashSet<u16>> {
-        match OUTPUTS.lock().unwrap().get(&out_id) {
+        match OUTPUTS.read().unwrap().get(&out_id) {
             Some(val)   => Some(val.clone()),
             None        => None
         }
     }
 
     fn add_request(req_id : u16, conn_id : u16) {
-        REQUESTS.lock().unwrap().insert(req_id, conn_id);
+        REQUESTS.write().unwrap().insert(req_id, conn_id);
+    }
+
+    fn remove_request(req_id : u16, conn_id : u16) {
+        let mut map = REQUESTS.write().unwrap();
+
+        // the request might have been associated to another connection in the meantime
+        if map.get(&req_id) == Some(&conn_id) {
+            map.remove(&req_id);
+        }
     }
 
     fn get_connection_from_request(req_id : u16) -> Option<u16> {
-        match REQUESTS.lock().unwrap().get(&req_id) {
+        match REQUESTS.read().unwrap().get(&req_id) {
             Some(val)   => Some(*val),
             None        => None
         }
     }
 
-    fn increment_nonce() {
+    /// Decrypt a management command (e.g., `set_key`, `disable`) sent by the deployer.
+    /// The internal management nonce is appended to the associated data, and the IV is
+    /// derived from it (see `Cipher::decrypt_management`): the deployer has no way to
+    /// influence it. The nonce is incremented (and persisted, if enabled) only if the
+    /// decryption succeeds. Once the nonce space is exhausted, all management commands
+    /// are refused. The plaintext is wiped on drop, as it usually is a key
+    fn decrypt_management(cipher : &[u8], ad : &[u8]) -> Result<Zeroizing<Vec<u8>>, ResultMessage> {
+        // keep the lock until the nonce is incremented, so that two concurrent
+        // commands cannot be authenticated with the same nonce
         let mut nonce_ref = NONCE.lock().unwrap();
-        *nonce_ref += 1;
+
+        let next_nonce = match nonce_ref.checked_add(1) {
+            Some(n) => n,
+            None    => {
+                error!("{}", Error::NonceExhausted);
+                return Err(nonce_exhausted())
+            }
+        };
+
+        let mut ad = ad.to_vec();
+        ad.extend_from_slice(&u64_to_data(*nonce_ref));
+
+        let plaintext = match Cipher::new(&MODULE_KEY, &Encryption::Aes)
+                .and_then(|c| c.decrypt_management(cipher, *nonce_ref, &ad).ok()) {
+            Some(p) => Zeroizing::new(p),
+            None    => return Err(failure(ResultCode::CryptoError, None))
+        };
+
+        // the nonce is saved before the command is executed, so that it cannot be replayed
+        if let Err(e) = persist_state(|s| s.nonce = next_nonce) {
+            error!("{}", e);
+            return Err(failure(ResultCode::InternalError, None))
+        }
+
+        *nonce_ref = next_nonce;
+
+        Ok(plaintext)
     }
 
-    fn check_nonce(nonce : u16) -> bool {
-        *NONCE.lock().unwrap() == nonce
+    /// Restore connections and management nonce from the persistence file (if any).
+    /// Called by the runner at startup, after the module key has been retrieved.
+    /// Persistence is refused in release builds, as it does not protect against rollback
+    pub fn restore_state() -> Result<(), Error> {
+        let path = match *PERSIST_FILE {
+            Some(p) => p,
+            None    => return Ok(())
+        };
+
+        if !cfg!(debug_assertions) {
+            error!("State persistence does not protect against rollback: it is only available in debug builds");
+            return Err(Error::PersistenceError)
+        }
+
+        let file = match std::fs::read(path) {
+            Ok(f)   => f,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()), // first start
+            Err(_)  => return Err(Error::PersistenceError)
+        };
+
+        // The file is: [version - cipher]
+        if file.len() < 8 {
+            return Err(Error::PersistenceError)
+        }
+
+        let version = data_to_u64(&file);
+        let key = persistence_key(version)?;
+        let state = match reactive_crypto::decrypt(&file[8..], &key, &file[..8], &Encryption::Aes) {
+            Ok(s)   => Zeroizing::new(s), // it contains the keys of the connections
+            Err(_)  => return Err(Error::CryptoError)
+        };
+
+        // The state is: [management_nonce - num_connections - (conn_id - connection)*]
+        let mut data = &state[..];
+        let nonce = data_to_u64(take(&mut data, 8).ok_or(Error::PersistenceError)?);
+        let num_conns = take(&mut data, 2).ok_or(Error::PersistenceError)?;
+        let mut connections = HashMap::new();
+
+        for _ in 0..data_to_u16(num_conns) {
+            let conn_id = data_to_u16(take(&mut data, 2).ok_or(Error::PersistenceError)?);
+            let conn = connection::Connection::deserialize(&mut data).ok_or(Error::PersistenceError)?;
+            // the connection is restored at its high-water mark
+            connections.insert(conn_id, conn.serialize());
+            register_connection(conn_id, conn);
+        }
+
+        *NONCE.lock().unwrap() = nonce;
+        *SNAPSHOT.lock().unwrap() = Snapshot { version, nonce, connections };
+
+        info!("Restored state version {} from {}", version, path);
+        Ok(())
+    }
+
+    /// Save the nonces of a connection up to `end` (excluded) before they are used. The
+    /// high-water mark is raised by `NONCE_BLOCK` nonces at a time, so that the state is
+    /// not saved on every event. Callers must hold the lock on the connection until the
+    /// nonces are used; if the state cannot be saved, the nonces must not be used
+    fn reserve_nonces(conn_id : u16, conn_ref : &Arc<Mutex<connection::Connection>>,
+            conn : &mut connection::Connection, end : u64) -> Result<(), Error> {
+        if PERSIST_FILE.is_none() || end <= conn.get_reserved() {
+            return Ok(())
+        }
+
+        let reserved = conn.get_reserved();
+        conn.set_reserved(end.saturating_add(NONCE_BLOCK));
+
+        let result = persist_state(|s| {
+            if is_registered(conn_id, conn_ref) {
+                s.connections.insert(conn_id, conn.serialize());
+            }
+        });
+
+        if result.is_err() {
+            conn.set_reserved(reserved);
+        }
+
+        result
+    }
+
+    /// Update the persisted state, and save it before returning.
+    /// Lock order: a connection or `NONCE`, then `SNAPSHOT`, then `CONNECTIONS`
+    fn persist_state(update : impl FnOnce(&mut Snapshot)) -> Result<(), Error> {
+        let path = match *PERSIST_FILE {
+            Some(p) => p,
+            None    => return Ok(())
+        };
+
+        // hold the lock until the file is written, so that snapshots are written in order
+        let mut snapshot = SNAPSHOT.lock().unwrap();
+        update(&mut snapshot);
+
+        let version = match snapshot.version.checked_add(1) {
+            Some(v) => v,
+            None    => return Err(Error::PersistenceError)
+        };
+
+        let state = snapshot.serialize();
+        let key = persistence_key(version)?;
+        let version_data = u64_to_data(version);
+
+        let cipher = match reactive_crypto::encrypt(&state, &key, &version_data, &Encryption::Aes) {
+            Ok(c)   => c,
+            Err(_)  => return Err(Error::CryptoError)
+        };
+
+        let mut file = version_data.to_vec();
+        file.extend_from_slice(&cipher);
+
+        // write to a temporary file first, so that a crash never leaves a partial snapshot
+        let tmp_path = format!("{}.tmp", path);
+        if std::fs::write(&tmp_path, &file).is_err() || std::fs::rename(&tmp_path, path).is_err() {
+            return Err(Error::PersistenceError)
+        }
+
+        snapshot.version = version;
+        Ok(())
+    }
+
+    /// Persist a change of the connections made by a management command
+    fn persist_connections(update : impl FnOnce(&mut HashMap<u16, Zeroizing<Vec<u8>>>)) {
+        if let Err(e) = persist_state(|s| update(&mut s.connections)) {
+            error!("{}", e);
+        }
+    }
+
+    /// HMAC-SHA256 over [label - message], under a key derived from the module key that
+    /// is only used by `attest` and `get_nonce`. The module key itself is never used to
+    /// authenticate unauthenticated requests, so that it cannot be used as an oracle
+    fn attestation_tag(label : &[u8], message : &[u8]) -> Result<Vec<u8>, Error> {
+        let mut key = Zeroizing::new([0u8; 32]);
+        if Hkdf::<Sha256>::new(None, &MODULE_KEY).expand(b"attestation", &mut *key).is_err() {
+            return Err(Error::InternalError)
+        }
+
+        let mut mac = match Hmac::<Sha256>::new_varkey(&*key) {
+            Ok(m)   => m,
+            Err(_)  => return Err(Error::InternalError)
+        };
+
+        mac.update(label);
+        mac.update(message);
+
+        Ok(mac.finalize().into_bytes().to_vec())
+    }
+
+    /// Each snapshot is encrypted with a different key, derived from the module key and the version
+    fn persistence_key(version : u64) -> Result<Zeroizing<Vec<u8>>, Error> {
+        let mut info = b"persistence".to_vec();
+        info.extend_from_slice(&u64_to_data(version));
+
+        let mut key = Zeroizing::new(vec![0u8; MODULE_KEY.len()]);
+        match Hkdf::<Sha256>::new(None, &MODULE_KEY).expand(&info, &mut key) {
+            Ok(_)   => Ok(key),
+            Err(_)  => Err(Error::InternalError)
+        }
     }
 }
@@ -0,0 +1,216 @@
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::time::Duration;
+
+/// Address of the module or of the Event Manager.
+/// Either a TCP address (IPv4, IPv6 or hostname) or a Unix domain socket
+pub enum Address {
+    Tcp(String),
+    Unix(String)
+}
+
+impl Address {
+    /// `host` is an IPv4/IPv6 address, a hostname or `unix:<path>`.
+    /// `port` is ignored for Unix domain sockets
+    pub fn new(host : &str, port : u16) -> Address {
+        if let Some(path) = host.strip_prefix("unix:") {
+            return Address::Unix(path.to_string())
+        }
+
+        // IPv6 addresses have to be enclosed in brackets
+        match host.contains(':') && !host.starts_with('[') {
+            true    => Address::Tcp(format!("[{}]:{}", host, port)),
+            false   => Address::Tcp(format!("{}:{}", host, port))
+        }
+    }
+
+    pub fn connect(&self) -> std::io::Result<Stream> {
+        match self {
+            Address::Tcp(addr)  => Ok(Stream::Tcp(TcpStream::connect(addr)?)),
+            #[cfg(unix)]
+            Address::Unix(path) => Ok(Stream::Unix(UnixStream::connect(path)?)),
+            #[cfg(not(unix))]
+            Address::Unix(_)    => Err(unsupported())
+        }
+    }
+
+    pub fn bind(&self) -> std::io::Result<Listener> {
+        match self {
+            Address::Tcp(addr)  => Ok(Listener::Tcp(TcpListener::bind(addr)?)),
+            #[cfg(unix)]
+            Address::Unix(path) => Ok(Listener::Unix(bind_unix(path)?)),
+            #[cfg(not(unix))]
+            Address::Unix(_)    => Err(unsupported())
+        }
+    }
+}
+
+/// Permissions of the socket file of the module: only the owner can connect
+#[cfg(unix)]
+const SOCKET_MODE : u32 = 0o600;
+
+#[cfg(unix)]
+fn bind_unix(path : &str) -> std::io::Result<UnixListener> {
+    // remove the socket left by a previous execution, if any (never remove other kind of files)
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            std::fs::remove_file(path)?;
+        }
+    }
+
+    if let Some(dir) = std::path::Path::new(path).parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+
+    let listener = UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(SOCKET_MODE))?;
+
+    Ok(listener)
+}
+
+impl std::fmt::Display for Address {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            Address::Tcp(addr)  => write!(f, "{}", addr),
+            Address::Unix(path) => write!(f, "unix:{}", path)
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn unsupported() -> std::io::Error {
+    std::io::Error::other("Unix domain sockets are not supported")
+}
+
+/// Get a host from the environment variable `var`, if set. Otherwise, return `default`
+pub fn host_from_env(var : &str, default : &str) -> String {
+    match std::env::var(var) {
+        Ok(host)    => host,
+        Err(_)      => default.to_string()
+    }
+}
+
+/// Get a port from the environment variable `var`, if set. Otherwise, return `default`
+pub fn port_from_env(var : &str, default : u16) -> u16 {
+    match std::env::var(var) {
+        Ok(port)    => port.parse().unwrap_or_else(|_| panic!("Invalid port in {}: {}", var, port)),
+        Err(_)      => default
+    }
+}
+
+pub enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream)
+}
+
+impl Stream {
+    pub fn set_read_timeout(&self, timeout : Option<Duration>) -> std::io::Result<()> {
+        match self {
+            Stream::Tcp(s)  => s.set_read_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(s) => s.set_read_timeout(timeout)
+        }
+    }
+
+    pub fn set_nonblocking(&self, nonblocking : bool) -> std::io::Result<()> {
+        match self {
+            Stream::Tcp(s)  => s.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            Stream::Unix(s) => s.set_nonblocking(nonblocking)
+        }
+    }
+
+    /// Read one byte in non-blocking mode: `None` if nothing has been received yet.
+    /// An error is returned if the connection has been closed by the peer
+    #[allow(dead_code)] // not used by the SGX runner
+    pub fn read_byte_nonblocking(&mut self) -> std::io::Result<Option<u8>> {
+        let mut byte = [0u8; 1];
+
+        match self.read(&mut byte) {
+            Ok(0)   => Err(std::io::ErrorKind::UnexpectedEof.into()),
+            Ok(_)   => Ok(Some(byte[0])),
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => Ok(None),
+            Err(e)  => Err(e)
+        }
+    }
+
+    /// Check, without blocking, that an idle connection can be reused: the peer has not
+    /// closed it and has not sent anything. Unexpected data is consumed, and the connection
+    /// must be dropped. Always `false` in SGX enclaves, where non-blocking mode has no effect
+    pub fn is_idle(&mut self) -> bool {
+        if cfg!(target_env = "sgx") || self.set_nonblocking(true).is_err() {
+            return false
+        }
+
+        let idle = match self.read(&mut [0u8; 1]) {
+            Err(e)  => e.kind() == std::io::ErrorKind::WouldBlock,
+            Ok(_)   => false // closed by the peer (0 bytes) or unexpected data
+        };
+
+        idle && self.set_nonblocking(false).is_ok()
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf : &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Stream::Tcp(s)  => s.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(s) => s.read(buf)
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf : &[u8]) -> std::io::Result<usize> {
+        match self {
+            Stream::Tcp(s)  => s.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(s) => s.write(buf)
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Stream::Tcp(s)  => s.flush(),
+            #[cfg(unix)]
+            Stream::Unix(s) => s.flush()
+        }
+    }
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener)
+}
+
+impl Listener {
+    #[allow(dead_code)] // not used by the SGX runner
+    pub fn set_nonblocking(&self, nonblocking : bool) -> std::io::Result<()> {
+        match self {
+            Listener::Tcp(l)    => l.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            Listener::Unix(l)   => l.set_nonblocking(nonblocking)
+        }
+    }
+
+    pub fn accept(&self) -> std::io::Result<Stream> {
+        match self {
+            Listener::Tcp(l)    => Ok(Stream::Tcp(l.accept()?.0)),
+            #[cfg(unix)]
+            Listener::Unix(l)   => Ok(Stream::Unix(l.accept()?.0))
+        }
+    }
+
+    /// Iterator over the incoming connections, like `TcpListener::incoming`
+    #[allow(dead_code)] // not used by the native runner
+    pub fn incoming(&self) -> impl Iterator<Item = std::io::Result<Stream>> + '_ {
+        std::iter::repeat_with(move || self.accept())
+    }
+}
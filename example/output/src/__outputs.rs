@@ -0,0 +1,45 @@
+// Outputs and requests declared with annotations in lib.rs, and the IDs of all
+// the outputs and requests of the module
+#![allow(dead_code)]
+
+#[allow(unused_imports)]
+use super::*;
+
+#[allow(non_upper_case_globals)]
+pub mod __ids {
+    pub const button_pressed : u16 = 16384;
+    pub const output1 : u16 = 16385;
+    pub const alarm : u16 = 16386;
+    pub const get_value : u16 = 32768;
+    pub const get_threshold : u16 = 32769;
+}
+
+pub fn button_pressed(data : &[u8]) -> Result<(), crate::__authentic_execution::authentic_execution::Error> {
+    crate::debug!("OUTPUT: button_pressed");
+	let id : u16 = crate::__outputs::__ids::button_pressed;
+
+    crate::__authentic_execution::authentic_execution::handle_output(id, data)
+}
+
+pub fn output1(data : &[u8]) -> Result<(), crate::__authentic_execution::authentic_execution::Error> {
+    crate::debug!("OUTPUT: output1");
+	let id : u16 = crate::__outputs::__ids::output1;
+
+    crate::__authentic_execution::authentic_execution::handle_output(id, data)
+}
+
+pub fn get_value(data : &[u8]) -> Result<Vec<u8>, crate::__authentic_execution::authentic_execution::Error> {
+    crate::debug!("REQUEST: get_value");
+	let id : u16 = crate::__outputs::__ids::get_value;
+
+    crate::__authentic_execution::authentic_execution::handle_request(id, data)
+}
+
+pub fn get_value_with_timeout(data : &[u8], timeout : std::time::Duration)
+        -> Result<Vec<u8>, crate::__authentic_execution::authentic_execution::Error> {
+    crate::debug!("REQUEST: get_value");
+	let id : u16 = crate::__outputs::__ids::get_value;
+
+    crate::__authentic_execution::authentic_execution::handle_request_with_timeout(id, data, Some(timeout))
+}
+
@@ -1,60 +1,139 @@
-use std::net::{TcpListener, TcpStream};
+use crate::__net::{Stream, Listener};
+use std::io::{ErrorKind, Read};
+use std::sync::mpsc::{channel, Receiver};
+use std::time::{Duration, Instant};
 use crate::{info, error};
-use crate::__authentic_execution::authentic_execution::{MODULE_NAME, EM_PORT, MODULE_ID, NUM_THREADS, handle_entrypoint};
+use crate::__authentic_execution::authentic_execution::{MODULE_ADDRESS, EM_ADDRESS, NUM_THREADS, handle_entrypoint,
+    restore_state};
 use threadpool::ThreadPool;
+use zeroize::Zeroizing;
 
 lazy_static! {
-    pub static ref MODULE_KEY: String = String::from("ZGvXsu+82adJOTcseF9b8A==");
+    pub static ref MODULE_KEY: Zeroizing<Vec<u8>> = Zeroizing::new(base64::decode("12cutiZLhbwwoX/rfC9PyQ==").unwrap());
 }
 
+/// Maximum time a connection can stay idle before being closed. Once a message
+/// starts to arrive, it must also be received completely within this time
+const IDLE_TIMEOUT : Duration = Duration::from_secs(30);
 
-fn handle_client(mut stream: TcpStream) {
-    let payload = match reactive_net::read_message(&mut stream) {
+/// Time to wait before checking the connections again, if none of them had a message
+const POLL_INTERVAL : Duration = Duration::from_millis(1);
+
+/// Serve a message, whose first byte has already been read. Returns false if the
+/// connection cannot be kept alive (e.g., it has been closed by the peer)
+fn handle_message(stream : &mut Stream, first : u8) -> bool {
+    if stream.set_nonblocking(false).is_err() || stream.set_read_timeout(Some(IDLE_TIMEOUT)).is_err() {
+        return false;
+    }
+
+    let payload = match reactive_net::read_message(&mut (&[first][..]).chain(&mut *stream)) {
         Ok(p) => p,
-        Err(e) => {
-            error!(&format!("{}", e));
-            return;
-        }
+        Err(_) => return false // connection closed by the peer, or timeout
     };
 
     let resp = handle_entrypoint(&payload);
 
-    if let Err(e) = reactive_net::write_result(&mut stream, &resp) {
-        error!(&format!("{}", e));
+    if let Err(e) = reactive_net::write_result(stream, &resp) {
+        error!("{}", e);
+        return false;
     }
+
+    true
+}
+
+/// A connection waiting for its next message, and the time since it is idle
+fn idle(stream : Stream) -> Option<(Stream, Instant)> {
+    stream.set_nonblocking(true).ok()?;
+    Some((stream, Instant::now()))
 }
 
+/// Accept connections and wait for their messages without blocking. A connection is
+/// handed to `serve` only once a message starts to arrive, so that idle connections
+/// (keep-alive) never hold a thread. The connections to keep alive come back from `served`
+fn poll_connections(listener : Listener, served : Receiver<Stream>, mut serve : impl FnMut(Stream, u8)) {
+    if let Err(e) = listener.set_nonblocking(true) {
+        error!("{}", e);
+        return;
+    }
+
+    let mut connections : Vec<(Stream, Instant)> = Vec::new();
 
-fn run_single_thread(listener : TcpListener) {
-    for stream in listener.incoming() {
-        //debug!("Received connection");
-        match stream {
-            Ok(s)   => handle_client(s),
-            Err(_)  => error!("ERROR unwrapping the stream")
+    loop {
+        let mut active = false;
+
+        loop {
+            match listener.accept() {
+                Ok(s)   => {
+                    connections.extend(idle(s));
+                    active = true;
+                },
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(_)  => {
+                    error!("ERROR unwrapping the stream");
+                    break;
+                }
+            }
+        }
+
+        connections.extend(served.try_iter().filter_map(idle));
+
+        let mut i = 0;
+        while i < connections.len() {
+            let (stream, since) = &mut connections[i];
+
+            match stream.read_byte_nonblocking() {
+                Ok(Some(first))                             => {
+                    let (stream, _) = connections.swap_remove(i);
+                    serve(stream, first);
+                    active = true;
+                },
+                Ok(None) if since.elapsed() < IDLE_TIMEOUT  => i += 1,
+                _                                           => {
+                    // closed by the peer, or idle timeout
+                    connections.swap_remove(i);
+                }
+            }
+        }
+
+        if !active {
+            std::thread::sleep(POLL_INTERVAL);
         }
-        //debug!("Connection ended");
     }
 }
 
-fn run_multithread(listener : TcpListener) {
-    let pool = ThreadPool::new(*NUM_THREADS - 1);
+fn run_single_thread(listener : Listener) {
+    let (sender, served) = channel();
 
-    for stream in listener.incoming() {
-        //debug!("Received connection");
-        match stream {
-            Ok(s)   => pool.execute(|| { handle_client(s) } ),
-            Err(_)  => error!("ERROR unwrapping the stream")
+    poll_connections(listener, served, |mut s, first| {
+        if handle_message(&mut s, first) {
+            let _ = sender.send(s);
         }
-        //debug!("Connection ended");
-    }
+    });
+}
+
+fn run_multithread(listener : Listener) {
+    let pool = ThreadPool::new(*NUM_THREADS - 1);
+    let (sender, served) = channel();
+
+    poll_connections(listener, served, |mut s, first| {
+        let sender = sender.clone();
+        pool.execute(move || {
+            if handle_message(&mut s, first) {
+                let _ = sender.send(s);
+            }
+        })
+    });
 }
 
 pub fn run() -> std::io::Result<()> {
-    let port = *EM_PORT + *MODULE_ID;
-    let host = format!("127.0.0.1:{}", port); // no one from outside can access SM
+    if let Err(e) = restore_state() {
+        error!("Failed to restore state: {}", e);
+        return Err(std::io::Error::other(e.to_string()));
+    }
 
-    info!(&format!("Listening on {}", host));
-    let listener = TcpListener::bind(host)?;
+    info!("Event Manager at {}", *EM_ADDRESS);
+    info!("Listening on {}", *MODULE_ADDRESS);
+    let listener = MODULE_ADDRESS.bind()?;
 
     match *NUM_THREADS {
         0   => panic!("NUM_THREADS is zero"),
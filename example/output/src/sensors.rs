@@ -0,0 +1,56 @@
+// Items declared with the macros of sm_macros, using typed data
+
+use std::sync::Mutex;
+use serde::{Serialize, Deserialize};
+use crate::{ResultCode, error};
+
+// types of typed inputs, entry points and handlers must be visible from the crate root
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct Reading {
+    pub id : u16,
+    pub value : f32
+}
+
+// outputs and requests can use the private types of this module
+#[derive(Serialize, Deserialize, Debug)]
+struct Alarm {
+    id : u16,
+    value : f32
+}
+
+static READINGS : Mutex<Vec<Reading>> = Mutex::new(Vec::new());
+
+sm_output!(alarm, Alarm);
+sm_request!(get_threshold, u16 -> f32);
+
+#[sm_input]
+pub fn new_reading(reading : Reading) {
+    let threshold = match get_threshold(&reading.id) {
+        Ok(t)   => t,
+        Err(e)  => {
+            error!("{}", e);
+            f32::MAX
+        }
+    };
+
+    if reading.value > threshold {
+        if let Err(e) = alarm(&Alarm { id : reading.id, value : reading.value }) {
+            error!("{}", e);
+        }
+    }
+
+    READINGS.lock().unwrap().push(reading);
+}
+
+#[sm_entry]
+pub fn num_readings(id : u16) -> u32 {
+    READINGS.lock().unwrap().iter().filter(|r| r.id == id).count() as u32
+}
+
+#[sm_handler]
+pub fn last_reading(id : u16) -> Result<Reading, ResultCode> {
+    match READINGS.lock().unwrap().iter().rev().find(|r| r.id == id) {
+        Some(r) => Ok(r.clone()),
+        None    => Err(ResultCode::BadRequest)
+    }
+}
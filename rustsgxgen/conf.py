@@ -4,23 +4,53 @@ from .runner import Runner
 DEFAULT_LOG_LEVEL = "info"
 STUBS_FOLDER = os.path.join(os.path.dirname(
     os.path.abspath(__file__)), "stubs")
+MACROS_FOLDER = os.path.join(os.path.dirname(
+    os.path.abspath(__file__)), "sm_macros")
 
 
 # Actual crates/modules
 # to be checked before adding, because it could be already present
 RUST_LAZY = "#[macro_use] extern crate lazy_static;\n"
-RUST_INSERT_INPUT = "\t\tm.insert({id}, crate::{name} as fn(&[u8]));\n"
-RUST_INSERT_ENTRY = "\t\tm.insert({id}, crate::{name} as fn(&[u8]) -> ResultMessage);\n"
-RUST_INSERT_HANDLER = "\t\tm.insert({id}, crate::{name} as fn(&[u8]) -> Vec<u8>);\n"
+# `path` is the path of the function in the crate (e.g., sensors::on_reading)
+RUST_INSERT_INPUT = "\t\tm.insert({id}, (|d : &[u8]| raw_input(crate::{path}, d)) as InputFn);\n"
+RUST_INSERT_ENTRY = "\t\tm.insert({id}, crate::{path} as EntryFn);\n"
+RUST_INSERT_HANDLER = "\t\tm.insert({id}, (|d : &[u8]| raw_handler(crate::{path}, d)) as HandlerFn);\n"
+# Handlers returning `Result<_, ResultCode>`
+RUST_INSERT_FALLIBLE_HANDLER = "\t\tm.insert({id}, crate::{path} as HandlerFn);\n"
+# Typed functions: the argument is deserialized, the return value serialized
+RUST_INSERT_TYPED_INPUT = "\t\tm.insert({id}, (|d : &[u8]| typed_input(crate::{path}, d)) as InputFn);\n"
+RUST_INSERT_TYPED_ENTRY = "\t\tm.insert({id}, (|d : &[u8]| typed_entry(crate::{path}, d)) as EntryFn);\n"
+RUST_INSERT_TYPED_HANDLER = "\t\tm.insert({id}, (|d : &[u8]| typed_handler(crate::{path}, d)) as HandlerFn);\n"
+RUST_INSERT_TYPED_FALLIBLE_HANDLER = ("\t\tm.insert({id}, (|d : &[u8]| typed_fallible_handler(crate::{path}, d))"
+                                      " as HandlerFn);\n")
+# ID of an output or request (see __outputs.rs)
+RUST_OUTPUT_ID = "    pub const {name} : u16 = {id};\n"
+# Outputs and requests declared outside of the crate root are generated in a file
+# of src/__outputs, included in a child module of the module that declares them,
+# which can see the private types of that module
+RUST_OUTPUTS_FILE = ("// Outputs and requests declared in crate::{path}\n"
+                     "#[allow(unused_imports)]\nuse super::*;\n{content}")
+# Import of the generated outputs and requests in the module that declares them
+RUST_USE_OUTPUTS = "#[allow(unused_imports)] use crate::__outputs::{{{names}}};"
+RUST_INCLUDE_OUTPUTS = ("#[allow(unused_imports)] use self::__sm_outputs::{{{names}}}; "
+                        "#[allow(dead_code)] mod __sm_outputs {{ include!(concat!(env!(\"CARGO_MANIFEST_DIR\"), "
+                        "\"/src/__outputs/{file}\")); }}")
 
 
 # Stubs
 STUB_MODS_USES = "mods_uses.rs"
 STUB_OUTPUT = "output.rs"
 STUB_REQUEST = "request.rs"
+STUB_OUTPUT_TYPED = "output_typed.rs"
+STUB_REQUEST_TYPED = "request_typed.rs"
 STUB_CONSTANTS = "constants.rs"
 STUB_MAIN = "main.rs"
 STUB_AUTH_EXEC = "__authentic_execution.rs"
+STUB_NET = "__net.rs"
+STUB_CIPHER = "__cipher.rs"
+STUB_OUTPUTS = "__outputs.rs"
+# Folder of the outputs and requests declared outside of the crate root
+OUTPUTS_DIR = "__outputs"
 CARGO_DEPENDENCIES = "common_deps.toml"
 
 DEFAULT_RUNNER = Runner.SGX
@@ -29,10 +59,43 @@ STUB_RUNNER_DEPS = "dependencies.toml"
 
 KEY_LENGTH = 16
 
+# Macros (sm_macros crate), copied to the output folder
+MACROS_OUTPUT = "__sm_macros"
+# set when compiling the module to collect the items declared with the macros
+METADATA_ENV = "SM_METADATA_FILE"
+
+# Threads serving the module (see runners)
+DEFAULT_NUM_THREADS = 1
+# Threads of the module that do not serve requests (i.e., output dispatcher)
+EXTRA_THREADS = 1
+# Enclave settings (SGX only), same defaults as Fortanix EDP
+DEFAULT_HEAP_SIZE = 0x2000000
+DEFAULT_STACK_SIZE = 0x20000
+
+# Default timeout of the requests, in milliseconds (0: no timeout)
+DEFAULT_REQUEST_TIMEOUT = 10000
+# Each request `name` also has a variant `name_with_timeout`, with a per-call timeout
+REQUEST_TIMEOUT_SUFFIX = "_with_timeout"
+
+DEFAULT_HOST = "127.0.0.1"
+UNIX_SOCKET_PREFIX = "unix:"
+
+# Transports between module and Event Manager
+TRANSPORT_TCP = "tcp"
+TRANSPORT_UNIX = "unix"
+DEFAULT_TRANSPORT = TRANSPORT_TCP
+DEFAULT_SOCKET_DIR = "/tmp/authentic-execution"
+EM_SOCKET_NAME = "em-{port}.sock"
+MODULE_SOCKET_NAME = "sm-{id}.sock"
+
 
 # Starting entrypoint index
-# 0 is set_key, 1 is attest, 2 is exit, 3 is handle_input, 4 is handle_handler
+# 0 is set_key, 1 is attest, 2 is disable, 3 is handle_input, 4 is handle_handler
 START_ENTRY_INDEX = 5
+# End (excluded) of the entrypoint indexes. The entry points added later take the
+# last IDs, so that the IDs of the entry points of existing modules do not change:
+# 16381 is remove_connection, 16382 is rekey, 16383 is get_nonce
+END_ENTRY_INDEX = 16381
 # Starting indexes of inputs, outputs, requests and handlers
 # They need to have different indexes, because the `index` field in Connection does
 # not distinguish between them. If the same index is used for different types, bad
@@ -44,29 +107,129 @@ START_OUTPUT_INDEX = 16384
 START_REQUEST_INDEX = 32768
 START_HANDLER_INDEX = 49152
 
+# Sections of the result JSON, with their starting index
+START_INDEXES = {
+    "inputs": START_INPUT_INDEX,
+    "outputs": START_OUTPUT_INDEX,
+    "entrypoints": START_ENTRY_INDEX,
+    "handlers": START_HANDLER_INDEX,
+    "requests": START_REQUEST_INDEX
+}
+
+# End (excluded) of the range of IDs of each section
+END_INDEXES = {
+    "inputs": START_INPUT_INDEX + 16384,
+    "outputs": START_OUTPUT_INDEX + 16384,
+    "entrypoints": END_ENTRY_INDEX,
+    "handlers": START_HANDLER_INDEX + 16384,
+    "requests": START_REQUEST_INDEX + 16384
+}
+
+# ID lock file, in the input folder: the IDs assigned to each name are kept
+# across generations, and the IDs of removed items are never reused
+ID_LOCK_FILE = "sm_ids.lock"
+ID_LOCK_RETIRED = "retired"
+ID_LOCK_HEADER = ("# IDs of the inputs, outputs, entry points, handlers and requests,\n"
+                  "# generated by rust-sgx-gen. Keep this file under version control\n")
+
+# Modules added to the crate (they are not parsed)
+GENERATED_MODULES = ["__authentic_execution", "__cipher", "__net", "__outputs", "__run"]
+
+# Names used in the diagnostics
+SECTION_NAMES = {
+    "inputs": "input",
+    "outputs": "output",
+    "entrypoints": "entry point",
+    "handlers": "handler",
+    "requests": "request"
+}
+
+# Names that cannot be used for inputs, outputs, entry points, handlers and
+# requests: items imported in lib.rs, generated modules and built-in entry points
+RESERVED_NAMES = {
+    "authentic_execution", "success", "failure", "handle_output", "handle_request",
+    "Error", "MODULE_NAME", "ResultCode", "ResultMessage", "main", "lazy_static",
+    "reactive_net", "sm_macros", "__authentic_execution", "__cipher", "__net",
+    "__outputs", "__sm_outputs", "__run", "set_key", "attest", "disable", "handle_input",
+    "handle_handler", "get_nonce", "rekey", "remove_connection"
+}
+
+# Kinds of the items declared with the macros, and their section
+METADATA_KINDS = {
+    "input": "inputs",
+    "output": "outputs",
+    "entry": "entrypoints",
+    "request": "requests",
+    "handler": "handlers"
+}
+
 
 # Regex
 # Note: [ \t] means space or tab. I use this when I don't explicitly allow newlines
 #   (e.g., in comments). \s means any space char (also newlines).
 #   This is for non-comment lines
+REGEX_THREADS = ("^[ \t]*//@[ \t]*sm_threads[ \t]*\([ \t]*(?P<num>[0-9]+)"
+                 "[ \t]*\)[ \t]*$")
+
+REGEX_MACROS = "#\s*\[\s*sm_(?:input|entry|handler)\s*[\](]|\\bsm_(?:output|request)\s*!"
+
+# Any annotation: the ones not matched by the regexes below are reported
+REGEX_ANNOTATION = "^[ \t]*//@[ \t]*(?P<kind>[^\s(]*)"
+
+# Expected syntax of each annotation, for the diagnostics
+ANNOTATION_HINTS = {
+    "sm_input": "expected `pub fn <name>(<arg> : <type>) {` on the next line",
+    "sm_entry": "expected `pub fn <name>(<arg> : <type>) -> <type> {` on the next line",
+    "sm_handler": "expected `pub fn <name>(<arg> : <type>) -> <type> {` on the next line",
+    "sm_output": "expected `//@ sm_output(<name>)` or `//@ sm_output(<name>, <type>)`",
+    "sm_request": "expected `//@ sm_request(<name>)` or `//@ sm_request(<name>, <type> -> <type>)`",
+    "sm_threads": "expected `//@ sm_threads(<threads>)`, in lib.rs"
+}
+
+# Items defined at module level (their names cannot be used by outputs and requests)
+REGEX_RUST_ITEM = ("\\b(?:fn|struct|enum|union|trait|type|const|static|mod|macro_rules\s*!)"
+                   "\s+(?:r#)?(?P<name>[_a-zA-Z][_a-zA-Z0-9]*)")
+
 REGEX_LAZY = "^\s*#\s*[\s*macro_use\s*]\s*extern\s*crate\s*lazy_static\s*;"
 
-REGEX_OUTPUT = ("^[ \t]*//@[ \t]*sm_output[ \t]*\([ \t]*"
-                "(?P<fname>[_a-zA-Z]+[_a-zA-Z0-9]*)[ \t]*\)[ \t]*$")
+#   All the annotations take an optional explicit ID, e.g., `//@ sm_input(id = 7)`
+#   or `//@ sm_output(name, id = 16390)`
+REGEX_ID = "[ \t]*id[ \t]*=[ \t]*(?P<id>[0-9]+)[ \t]*"
 
-REGEX_INPUT = ("^[ \t]*//@[ \t]*sm_input[ \t]*\n\s*pub\s+fn\s+"
+#   Outputs and requests may declare the types of their data, e.g.,
+#   `//@ sm_output(name, Type)` and `//@ sm_request(name, Type -> ResponseType)`
+REGEX_OUTPUT = ("^[ \t]*//@[ \t]*sm_output[ \t]*\([ \t]*"
+                "(?P<fname>[_a-zA-Z]+[_a-zA-Z0-9]*)[ \t]*"
+                "(?:,(?![ \t]*id[ \t]*=)[ \t]*(?P<arg>[^\n]+?))?"
+                "(?:[ \t]*," + REGEX_ID + ")?[ \t]*\)[ \t]*$")
+
+#   Inputs, entry points and handlers take either `&[u8]` (raw data) or any
+#   deserializable type (typed data). `arg` is the type of the argument, `ret`
+#   the return type
+REGEX_INPUT = ("^[ \t]*//@[ \t]*sm_input[ \t]*(?:\(" + REGEX_ID + "\)[ \t]*)?\n\s*pub\s+fn\s+"
                "(?P<fname>[_a-zA-Z]+[_a-zA-Z0-9]*)\s*\(\s*"
-               "[_a-zA-Z]+[_a-zA-Z0-9]*\s*:\s*&\s*\[\s*u8\s*]\s*\)\s*\{")
+               "[_a-zA-Z]+[_a-zA-Z0-9]*\s*:\s*(?P<arg>[^{;]+?)\s*\)\s*\{")
 
-REGEX_ENTRY = ("^[ \t]*//@[ \t]*sm_entry[ \t]*\n\s*pub\s+fn\s+"
+REGEX_ENTRY = ("^[ \t]*//@[ \t]*sm_entry[ \t]*(?:\(" + REGEX_ID + "\)[ \t]*)?\n\s*pub\s+fn\s+"
                "(?P<fname>[_a-zA-Z]+[_a-zA-Z0-9]*)\s*\(\s*"
-               "[_a-zA-Z]+[_a-zA-Z0-9]*\s*:\s*&\s*\[\s*u8\s*]\s*\)\s*->\s*"
-               "ResultMessage\s*\{")
+               "[_a-zA-Z]+[_a-zA-Z0-9]*\s*:\s*(?P<arg>[^{;]+?)\s*\)\s*"
+               "(?:->\s*(?P<ret>[^{;]+?)\s*)?\{")
 
 REGEX_REQUEST = ("^[ \t]*//@[ \t]*sm_request[ \t]*\([ \t]*(?P<fname>[_a-zA-Z]+"
-                 "[_a-zA-Z0-9]*)[ \t]*\)[ \t]*$")
+                 "[_a-zA-Z0-9]*)[ \t]*(?:,[ \t]*(?P<arg>[^\n]+?)[ \t]*->"
+                 "[ \t]*(?P<ret>[^\n]+?))?(?:[ \t]*," + REGEX_ID + ")?[ \t]*\)[ \t]*$")
 
-REGEX_HANDLER = ("^[ \t]*//@[ \t]*sm_handler[ \t]*\n\s*pub\s+fn\s+"
+REGEX_HANDLER = ("^[ \t]*//@[ \t]*sm_handler[ \t]*(?:\(" + REGEX_ID + "\)[ \t]*)?\n\s*pub\s+fn\s+"
                  "(?P<fname>[_a-zA-Z]+[_a-zA-Z0-9]*)\s*\(\s*[_a-zA-Z]+"
-                 "[_a-zA-Z0-9]*\s*:\s*&\s*\[\s*u8\s*]\s*\)\s*->\s*"
-                 "Vec\s*<\s*u8\s*>\s*\{")
+                 "[_a-zA-Z0-9]*\s*:\s*(?P<arg>[^{;]+?)\s*\)\s*->\s*"
+                 "(?P<ret>[^{;]+?)\s*\{")
+
+# Types of raw data
+REGEX_RAW_ARG = "&\s*\[\s*u8\s*]"
+REGEX_RAW_ENTRY_RET = "(?:[_a-zA-Z0-9]+\s*::\s*)*ResultMessage"
+REGEX_RAW_HANDLER_RET = "Vec\s*<\s*u8\s*>"
+
+# Handlers can fail, returning `Result<T, ResultCode>`. `ok` is the type of the
+# data returned on success
+REGEX_HANDLER_RESULT = ("(?:[_a-zA-Z0-9]+\s*::\s*)*Result\s*<\s*(?P<ok>.+?)\s*,\s*"
+                        "(?:[_a-zA-Z0-9]+\s*::\s*)*ResultCode\s*>")
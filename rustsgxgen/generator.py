@@ -2,29 +2,100 @@ import logging
 import os
 import sys
 import re
+import shutil
 import base64
 import toml
+from distutils import dir_util
 
 from . import conf
 from .utils import _parse_annotations, _write_module_info, _prepare_output_dir, \
     _check_input_module, _copy_main, _add_fields, \
-    _generate_key
+    _generate_key, _get_hosts, _get_ports, _parse_threads, _uses_macros, \
+    _collect_metadata, _add_metadata, _write_outputs, _load_id_lock, _write_id_lock, \
+    _rust_string, Error
 from .initialization import _set_parser, _set_logging
 
 
+def __write_auth_exec(out_src, items, constants_args):
+    with open(os.path.join(conf.STUBS_FOLDER, conf.STUB_CONSTANTS), "r") as f:
+        constants = f.read()
+
+    # add inputs entrypoints, handlers functions to hashmaps, so that they can
+    # be called given their ID
+    # typed functions are wrapped, to (de)serialize their data
+    # handlers that return `Result<_, ResultCode>` are wrapped only if typed
+    # functions are referred to by their full path, as they can be declared in
+    # any module of the crate
+    data, typed, paths = items.data, items.typed, items.paths
+
+    inputs = data["inputs"]
+    inputs_fn = ""
+    for _input in inputs:
+        insert = conf.RUST_INSERT_TYPED_INPUT if _input in typed["inputs"] \
+            else conf.RUST_INSERT_INPUT
+        inputs_fn += insert.format(id=inputs[_input], path=paths["inputs"][_input])
+
+    entrypoints = data["entrypoints"]
+    entrypoints_fn = ""
+    for entry in entrypoints:
+        insert = conf.RUST_INSERT_TYPED_ENTRY if entry in typed["entrypoints"] \
+            else conf.RUST_INSERT_ENTRY
+        entrypoints_fn += insert.format(id=entrypoints[entry],
+                                        path=paths["entrypoints"][entry])
+
+    handlers = data["handlers"]
+    handlers_fn = ""
+    for handler in handlers:
+        if handler in typed["handlers"]:
+            insert = conf.RUST_INSERT_TYPED_FALLIBLE_HANDLER if handler in items.fallible \
+                else conf.RUST_INSERT_TYPED_HANDLER
+        else:
+            insert = conf.RUST_INSERT_FALLIBLE_HANDLER if handler in items.fallible \
+                else conf.RUST_INSERT_HANDLER
+        handlers_fn += insert.format(id=handlers[handler],
+                                     path=paths["handlers"][handler])
+
+    # format constants with module's info
+    constants = constants.format(inputs=inputs_fn, entrypoints=entrypoints_fn,
+                                 handlers=handlers_fn, **constants_args)
+
+    # add constants to authentic_execution file, add the file to project
+    with open(os.path.join(conf.STUBS_FOLDER, conf.STUB_AUTH_EXEC), "r") as f:
+        auth_exec = f.read()
+
+    auth_exec = auth_exec.replace("{CONSTANTS}", constants)
+
+    with open(os.path.join(out_src, conf.STUB_AUTH_EXEC), "w") as f:
+        f.write(auth_exec)
+
+
 def __run(args, cargo):
     out_src = os.path.join(args.output, "src")
     module_name = cargo["package"]["name"]
 
     ## lib.rs file ##
     # In this section, we update lib.rs:
-    # - parse the annotations (inputs, outputs, entry points)
+    # - parse the annotations (inputs, outputs, entry points) of all the
+    #   modules of the crate
     # - add imports (for authentic execution functions and constants)
+    # The code of the developer is not changed: lines are only added at the end
+    # of the modules, so that the compiler errors point to the same lines of
+    # the input module
 
     lib_file = os.path.join(out_src, "lib.rs")
 
-    # parse annotations
-    content, data = _parse_annotations(lib_file)
+    # parse annotations (the imports of the generated outputs and requests are
+    # added to the files of the other modules in place)
+    content, items = _parse_annotations(out_src)
+
+    # assign IDs: names keep the IDs of the previous generations
+    id_lock = args.id_lock or os.path.join(args.input, conf.ID_LOCK_FILE)
+    lock = _load_id_lock(id_lock)
+    items.assign(lock)
+
+    # number of threads: command line, then annotation, then default
+    num_threads = args.threads or _parse_threads(
+        content) or conf.DEFAULT_NUM_THREADS
 
     # read imports
     with open(os.path.join(conf.STUBS_FOLDER, conf.STUB_MODS_USES), "r") as f:
@@ -35,8 +106,10 @@ def __run(args, cargo):
     if not re.match(conf.REGEX_LAZY, content, re.MULTILINE):
         lazy = conf.RUST_LAZY
 
-    # write new content
-    complete_lib = lazy + "\n" + mods_uses + "\n" + content
+    # write new content: the imports are appended to the code of the developer
+    if content and not content.endswith("\n"):
+        content += "\n"
+    complete_lib = content + "\n" + lazy + mods_uses
     with open(lib_file, "w") as f:
         f.write(complete_lib)
 
@@ -45,42 +118,54 @@ def __run(args, cargo):
     # we also need to update some data structures with the information retrieved
     # before
 
-    with open(os.path.join(conf.STUBS_FOLDER, conf.STUB_CONSTANTS), "r") as f:
-        constants = f.read()
-
-    # add inputs entrypoints, handlers functions to hashmaps, so that they can
-    # be called given their ID
-    inputs = data["inputs"]
-    inputs_fn = ""
-    for _input in inputs:
-        inputs_fn += conf.RUST_INSERT_INPUT.format(
-            id=inputs[_input], name=_input)
-
-    entrypoints = data["entrypoints"]
-    entrypoints_fn = ""
-    for entry in entrypoints:
-        entrypoints_fn += conf.RUST_INSERT_ENTRY.format(
-            id=entrypoints[entry], name=entry)
-
-    handlers = data["handlers"]
-    handlers_fn = ""
-    for handler in handlers:
-        handlers_fn += conf.RUST_INSERT_HANDLER.format(
-            id=handlers[handler], name=handler)
-
-    # format constants with module's info
-    constants = constants.format(id=args.moduleid, em_port=args.emport,
-                                 name=module_name, inputs=inputs_fn,
-                                 entrypoints=entrypoints_fn, handlers=handlers_fn)
-
-    # add constants to authentic_execution file, add the file to project
-    with open(os.path.join(conf.STUBS_FOLDER, conf.STUB_AUTH_EXEC), "r") as f:
-        auth_exec = f.read()
-
-    auth_exec = auth_exec.replace("{CONSTANTS}", constants)
-
-    with open(os.path.join(out_src, conf.STUB_AUTH_EXEC), "w") as f:
-        f.write(auth_exec)
+    # persistence of the state (disabled if no file is provided)
+    persist_file = "None"
+    if args.persist_file:
+        if not args.runner.supports_persistence():
+            raise Error("State persistence is not supported by the selected runner")
+        persist_file = f"Some({_rust_string(args.persist_file)})"
+        logging.warning(
+            "State persistence does not protect against rollback of the state file: "
+            "it is only available in debug builds of the module")
+
+    # default timeout of the requests (disabled if 0)
+    request_timeout = "None"
+    if args.request_timeout:
+        request_timeout = f"Some(Duration::from_millis({args.request_timeout}))"
+
+    # ports and addresses of the EM and of the module
+    module_port, attestation_port = _get_ports(args)
+    em_host, module_host = _get_hosts(args)
+    for host in [em_host, module_host]:
+        if host.startswith(conf.UNIX_SOCKET_PREFIX) and not args.runner.supports_unix_sockets():
+            raise Error("Unix domain sockets are not supported by the selected runner")
+
+    # module's info
+    constants_args = {
+        "id": args.moduleid,
+        "em_port": args.emport,
+        "module_port": module_port,
+        "attestation_port": attestation_port,
+        "em_host": _rust_string(em_host.strip("[]")),
+        "module_host": _rust_string(module_host.strip("[]")),
+        "name": _rust_string(module_name),
+        "num_threads": num_threads,
+        "persist_file": persist_file,
+        "request_timeout": request_timeout
+    }
+
+    __write_auth_exec(out_src, items, constants_args)
+
+    # add network utilities (addresses, streams and listeners)
+    shutil.copy(os.path.join(conf.STUBS_FOLDER, conf.STUB_NET),
+                os.path.join(out_src, conf.STUB_NET))
+
+    # add the cipher contexts of the connections
+    shutil.copy(os.path.join(conf.STUBS_FOLDER, conf.STUB_CIPHER),
+                os.path.join(out_src, conf.STUB_CIPHER))
+
+    # IDs of the outputs and requests
+    _write_outputs(out_src, items)
 
     ## Main and other files ##
     # Here, we will add the logic for main(): the project will not be a Cargo lib
@@ -109,8 +194,8 @@ def __run(args, cargo):
         encoded_key = None
         if args.spkey:
             with open(args.spkey, "r") as f:
-                sp_key = f.read() + "\\0"
-                runner_file = runner_file.replace("__SP_VKEY_PEM__", sp_key)
+                sp_key = f.read() + "\0"
+                runner_file = runner_file.replace('"__SP_VKEY_PEM__"', _rust_string(sp_key))
         else:
             logging.warning(
                 "ra_sp public key not provided as input! RA won't work")
@@ -137,18 +222,50 @@ def __run(args, cargo):
     except Exception:
         logging.warning("Runner dependencies file not found")
 
+    # enclave settings: one TCS for each thread of the module, and one for each
+    # thread serving the module to wait for the result of a request with a timeout
+    if runner.has_enclave_settings():
+        cargo["package"].setdefault("metadata", {})["fortanix-sgx"] = {
+            "threads": 2 * num_threads + conf.EXTRA_THREADS,
+            "heap-size": args.heap_size,
+            "stack-size": args.stack_size
+        }
+
     # write to cargo
     with open(os.path.join(args.output, "Cargo.toml"), "w") as f:
         toml.dump(cargo, f)
 
+    # add the macros crate (see common dependencies)
+    dir_util.copy_tree(conf.MACROS_FOLDER,
+                       os.path.join(args.output, conf.MACROS_OUTPUT))
+
+    ## Items declared with macros ##
+    # The module is compiled once to collect them, then the generated code is
+    # updated accordingly
+
+    if _uses_macros(out_src):
+        logging.debug("Collecting items declared with macros..")
+        metadata = _collect_metadata(args.output)
+        _add_metadata(items, metadata, args.output)
+        items.assign(lock)
+        _write_outputs(out_src, items)
+        __write_auth_exec(out_src, items, constants_args)
+
+    # update the ID lock file
+    _write_id_lock(id_lock, items, lock)
+
     # write module info to output file (if specified)
     if args.print:
+        ports = {"module_port": module_port}
+        if not runner.has_hardcoded_key():
+            ports["attestation_port"] = attestation_port
+
         _write_module_info(args.print, module_name,
-                           args.moduleid, data, encoded_key)
+                           args.moduleid, items.data, encoded_key, ports)
 
     logging.debug("Done")
 
-    return data, master_key
+    return items.data, master_key
 
 
 def generate(args):
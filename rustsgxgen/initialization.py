@@ -1,6 +1,8 @@
 import argparse
+import ipaddress
 import logging
 import os
+import re
 import colorlog
 
 from . import conf
@@ -17,15 +19,45 @@ def _set_parser():
     parser.add_argument('-o', '--output', required=True,
                         type=__output_dir, help='Output folder of the software module')
     parser.add_argument('-m', '--moduleid', required=True,
-                        type=__int16bits, help='16-bit Module ID')
+                        type=__int16bits("Module ID"), help='16-bit Module ID')
     parser.add_argument('-e', '--emport', required=True,
-                        type=__int16bits, help='EM TCP port')
+                        type=__int16bits("EM port"), help='EM TCP port')
+    parser.add_argument('--module-port', required=False, type=__int16bits("module port"),
+                        help='Listen port of the module. Default: EM port + module ID')
+    parser.add_argument('--attestation-port', required=False,
+                        type=__int16bits("attestation port"),
+                        help='Remote Attestation port (SGX only). Default: module port')
+    parser.add_argument('-t', '--transport', required=False, default=conf.DEFAULT_TRANSPORT,
+                        choices=[conf.TRANSPORT_TCP, conf.TRANSPORT_UNIX],
+                        help='Transport between module and EM')
+    parser.add_argument('--socket-dir', required=False, default=conf.DEFAULT_SOCKET_DIR,
+                        help='Directory of the Unix domain sockets (unix transport only)')
+    parser.add_argument('--em-host', required=False,
+                        type=__host, help='EM address: IPv4/IPv6 address, hostname or unix:<path>')
+    parser.add_argument('--module-host', required=False,
+                        type=__host,
+                        help='Listen address of the module: IPv4/IPv6 address, hostname or unix:<path>')
     parser.add_argument('-r', '--runner', type=__runner, required=False,
-                        default=conf.DEFAULT_RUNNER, help='Runner name')
+                        default=conf.DEFAULT_RUNNER,
+                        help='Runner name: native or sgx. SGX modules do not reuse connections '
+                        'to the EM: they open a new one for every output and request')
     parser.add_argument('-s', '--spkey', required=False,
                         type=__sp_key, help='Path to ra_sp public key')
     parser.add_argument(
         '-p', '--print', help='Output JSON file (module infos)')
+    parser.add_argument('--id-lock', required=False,
+                        help=f'ID lock file. Default: {conf.ID_LOCK_FILE} in the input folder')
+    parser.add_argument('-n', '--threads', required=False, type=__positive_int,
+                        help='Number of threads serving the module. Overrides sm_threads')
+    parser.add_argument('--heap-size', required=False, default=conf.DEFAULT_HEAP_SIZE,
+                        type=__positive_int, help='Enclave heap size in bytes (SGX only)')
+    parser.add_argument('--stack-size', required=False, default=conf.DEFAULT_STACK_SIZE,
+                        type=__positive_int, help='Enclave stack size per thread in bytes (SGX only)')
+    parser.add_argument('--persist-file', required=False,
+                        help='Path of the file where the module persists its state (native only)')
+    parser.add_argument('--request-timeout', required=False, default=conf.DEFAULT_REQUEST_TIMEOUT,
+                        type=__non_negative_int,
+                        help='Default timeout (milliseconds) of the requests. 0: no timeout')
     return parser
 
 
@@ -52,11 +84,30 @@ def _set_logging(loglevel):
     log.addHandler(stream_handler)
 
 
-def __int16bits(arg):
+def __int16bits(name):
+    def check(arg):
+        arg = int(arg)
+        if arg < 0 or arg > 65535:
+            raise argparse.ArgumentTypeError(
+                f"Invalid {name}: must be between 0 and 65535")
+
+        return arg
+
+    return check
+
+
+def __positive_int(arg):
+    arg = int(arg, 0)
+    if arg <= 0:
+        raise argparse.ArgumentTypeError("Value must be positive")
+
+    return arg
+
+
+def __non_negative_int(arg):
     arg = int(arg)
-    if arg < 0 or arg > 65535:
-        raise argparse.ArgumentTypeError(
-            "Invalid Module ID: must be between 0 and 65535")
+    if arg < 0:
+        raise argparse.ArgumentTypeError("Value must be non-negative")
 
     return arg
 
@@ -71,6 +122,24 @@ def __str16bytes(arg):
     return "_" * remaining + arg
 
 
+def __host(arg):
+    if arg.startswith(conf.UNIX_SOCKET_PREFIX):
+        if len(arg) == len(conf.UNIX_SOCKET_PREFIX):
+            raise argparse.ArgumentTypeError("Empty Unix domain socket path")
+        return arg
+
+    try:
+        ipaddress.ip_address(arg.strip("[]"))
+        return arg
+    except ValueError:
+        pass
+
+    if re.match(r"^[a-zA-Z0-9]([a-zA-Z0-9.-]*[a-zA-Z0-9])?$", arg):
+        return arg
+
+    raise argparse.ArgumentTypeError(f"Invalid host: {arg}")
+
+
 def __runner(arg):
     try:
         return Runner.from_str(arg)
@@ -33,3 +33,23 @@ class Runner(Enum):
             return True
 
         return False
+
+    def has_enclave_settings(self):
+        if self == Runner.SGX:
+            return True
+
+        return False
+
+    def supports_unix_sockets(self):
+        # Fortanix EDP does not provide Unix domain sockets
+        if self == Runner.NATIVE:
+            return True
+
+        return False
+
+    def supports_persistence(self):
+        # Fortanix EDP does not provide access to the file system
+        if self == Runner.NATIVE:
+            return True
+
+        return False
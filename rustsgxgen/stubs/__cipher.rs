@@ -0,0 +1,115 @@
+use std::convert::TryInto;
+use aes_gcm::{Aes128Gcm, KeyInit};
+use aes_gcm::aead::AeadInPlace;
+use reactive_crypto::Encryption;
+use zeroize::Zeroizing;
+
+/// Length of the AES-GCM tag, appended to the ciphertext
+const AES_TAG_LENGTH : usize = 16;
+
+/// Header of a message: [epoch - nonce]. It is the associated data of the message
+/// and, for AES-GCM, also the 96-bit IV, which is never reused under the same key:
+/// the nonce is never reused within an epoch, and a new epoch comes with a new key
+fn header(epoch : u32, nonce : u64) -> [u8; 12] {
+    let mut header = [0u8; 12];
+    header[..4].copy_from_slice(&epoch.to_be_bytes());
+    header[4..].copy_from_slice(&nonce.to_be_bytes());
+    header
+}
+
+#[derive(Debug)]
+pub struct CryptoError;
+
+impl std::fmt::Display for CryptoError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(f, "CryptoError")
+    }
+}
+
+/// Cipher context of a connection. The key schedule is computed once, when the
+/// context is created, and reused for all the messages of the connection.
+/// The output is [ciphertext - tag], authenticated with the message header.
+/// Key and key schedule are wiped on drop
+pub enum Cipher {
+    Aes(Box<Aes128Gcm>),
+    // no prepared context is available for Spongent: reactive_crypto is used
+    Spongent(Zeroizing<Vec<u8>>)
+}
+
+impl Cipher {
+    /// Returns `None` if `key` is not a valid key for `encryption`
+    pub fn new(key : &[u8], encryption : &Encryption) -> Option<Cipher> {
+        match encryption {
+            Encryption::Aes         => {
+                Some(Cipher::Aes(Box::new(Aes128Gcm::new_from_slice(key).ok()?)))
+            },
+            Encryption::Spongent    => Some(Cipher::Spongent(Zeroizing::new(key.to_vec())))
+        }
+    }
+
+    /// Encrypt message `nonce` of `epoch`, appending ciphertext and tag to `out`.
+    /// The plaintext is copied once into `out` and encrypted in place
+    pub fn encrypt(&self, plaintext : &[u8], epoch : u32, nonce : u64, out : &mut Vec<u8>) -> Result<(), CryptoError> {
+        let header = header(epoch, nonce);
+
+        match self {
+            Cipher::Aes(cipher)     => {
+                let start = out.len();
+                out.reserve(plaintext.len() + AES_TAG_LENGTH);
+                out.extend_from_slice(plaintext);
+
+                match cipher.encrypt_in_place_detached(&header.into(), &header, &mut out[start..]) {
+                    Ok(tag) => {
+                        out.extend_from_slice(&tag);
+                        Ok(())
+                    },
+                    Err(_)  => {
+                        out.truncate(start);
+                        Err(CryptoError)
+                    }
+                }
+            },
+            Cipher::Spongent(key)   => {
+                let cipher = reactive_crypto::encrypt(plaintext, key, &header, &Encryption::Spongent)
+                    .map_err(|_| CryptoError)?;
+                out.extend_from_slice(&cipher);
+                Ok(())
+            }
+        }
+    }
+
+    /// Decrypt message `nonce` of `epoch` (tag included). The ciphertext is copied
+    /// once into the returned buffer and decrypted in place
+    pub fn decrypt(&self, cipher : &[u8], epoch : u32, nonce : u64) -> Result<Vec<u8>, CryptoError> {
+        let header = header(epoch, nonce);
+        self.decrypt_with(cipher, &header, &header)
+    }
+
+    /// Decrypt a management command sent with the management `nonce`. The IV is the header
+    /// of message `nonce` of epoch 0, while the associated data `ad` is given by the command
+    pub fn decrypt_management(&self, cipher : &[u8], nonce : u64, ad : &[u8]) -> Result<Vec<u8>, CryptoError> {
+        self.decrypt_with(cipher, &header(0, nonce), ad)
+    }
+
+    fn decrypt_with(&self, cipher : &[u8], iv : &[u8; 12], ad : &[u8]) -> Result<Vec<u8>, CryptoError> {
+        match self {
+            Cipher::Aes(aes)        => {
+                if cipher.len() < AES_TAG_LENGTH {
+                    return Err(CryptoError)
+                }
+
+                let (ciphertext, tag) = cipher.split_at(cipher.len() - AES_TAG_LENGTH);
+                let tag : [u8; AES_TAG_LENGTH] = tag.try_into().map_err(|_| CryptoError)?;
+                let mut data = ciphertext.to_vec();
+
+                aes.decrypt_in_place_detached(iv.into(), ad, &mut data, &tag.into())
+                    .map_err(|_| CryptoError)?;
+
+                Ok(data)
+            },
+            Cipher::Spongent(key)   => {
+                reactive_crypto::decrypt(cipher, key, ad, &Encryption::Spongent).map_err(|_| CryptoError)
+            }
+        }
+    }
+}
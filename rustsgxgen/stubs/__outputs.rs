@@ -0,0 +1,11 @@
+// Outputs and requests declared with annotations in lib.rs, and the IDs of all
+// the outputs and requests of the module
+#![allow(dead_code)]
+
+#[allow(unused_imports)]
+use super::*;
+
+#[allow(non_upper_case_globals)]
+pub mod __ids {{
+{ids}}}
+{outputs}
@@ -1,25 +1,35 @@
     lazy_static! {{
         pub static ref MODULE_ID: u16 = {id};
-        pub static ref MODULE_NAME: &'static str = "{name}";
-        pub static ref EM_PORT: u16 = {em_port};
-        pub static ref NUM_THREADS: usize = 1; //TODO assign custom value during code generation
-        static ref INPUTS: std::collections::HashMap<u16, fn(&[u8])> = {{
+        pub static ref MODULE_NAME: &'static str = {name};
+        pub static ref EM_PORT: u16 = port_from_env("EM_PORT", {em_port});
+        pub static ref EM_ADDRESS: Address = Address::new(&host_from_env("EM_HOST", {em_host}), *EM_PORT);
+        pub static ref MODULE_PORT: u16 = port_from_env("MODULE_PORT", {module_port});
+        pub static ref ATTESTATION_PORT: u16 = {attestation_port};
+        pub static ref MODULE_ADDRESS: Address = Address::new(&host_from_env("MODULE_HOST", {module_host}),
+            *MODULE_PORT);
+        pub static ref NUM_THREADS: usize = {num_threads};
+        pub static ref PERSIST_FILE: Option<&'static str> = {persist_file};
+        pub static ref REQUEST_TIMEOUT: Option<Duration> = {request_timeout};
+        static ref INPUTS: std::collections::HashMap<u16, InputFn> = {{
             #[allow(unused_mut)]
             let mut m = std::collections::HashMap::new();
     {inputs}
             m
         }};
-        static ref ENTRYPOINTS: std::collections::HashMap<u16, fn(&[u8]) -> ResultMessage> = {{
+        static ref ENTRYPOINTS: std::collections::HashMap<u16, EntryFn> = {{
             let mut m = std::collections::HashMap::new();
-            m.insert(0, set_key_wrapper as fn(&[u8]) -> ResultMessage);
-            m.insert(1, attest_wrapper as fn(&[u8]) -> ResultMessage);
-            m.insert(2, disable_wrapper as fn(&[u8]) -> ResultMessage);
-            m.insert(3, handle_input_wrapper as fn(&[u8]) -> ResultMessage);
-            m.insert(4, handle_handler_wrapper as fn(&[u8]) -> ResultMessage);
+            m.insert(0, set_key_wrapper as EntryFn);
+            m.insert(1, attest_wrapper as EntryFn);
+            m.insert(2, disable_wrapper as EntryFn);
+            m.insert(3, handle_input_wrapper as EntryFn);
+            m.insert(4, handle_handler_wrapper as EntryFn);
+            m.insert(16381, remove_connection_wrapper as EntryFn);
+            m.insert(16382, rekey_wrapper as EntryFn);
+            m.insert(16383, get_nonce_wrapper as EntryFn);
     {entrypoints}
             m
         }};
-        static ref HANDLERS: std::collections::HashMap<u16, fn(&[u8]) -> Vec<u8>> = {{
+        static ref HANDLERS: std::collections::HashMap<u16, HandlerFn> = {{
             #[allow(unused_mut)]
             let mut m = std::collections::HashMap::new();
     {handlers}
@@ -1,6 +1,10 @@
 extern crate reactive_net;
+#[allow(unused_imports)] #[macro_use] extern crate sm_macros;
 
 mod __authentic_execution;
+mod __cipher;
+mod __net;
+mod __outputs;
 pub mod __run;
 
 #[allow(unused_imports)] use __authentic_execution::authentic_execution;
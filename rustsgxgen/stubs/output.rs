@@ -1,7 +1,7 @@
 
-pub fn {name}(data : &[u8]) {{
-    debug!("OUTPUT: {name}");
-	let id : u16 = {id};
+pub fn {name}(data : &[u8]) -> Result<(), crate::__authentic_execution::authentic_execution::Error> {{
+    crate::debug!("OUTPUT: {name}");
+	let id : u16 = crate::__outputs::__ids::{name};
 
-    handle_output(id, data);
+    crate::__authentic_execution::authentic_execution::handle_output(id, data)
 }}
@@ -0,0 +1,8 @@
+
+pub fn {name}(data : &{arg}) -> Result<(), crate::__authentic_execution::authentic_execution::Error> {{
+    crate::debug!("OUTPUT: {name}");
+	let id : u16 = crate::__outputs::__ids::{name};
+
+    let data = crate::__authentic_execution::authentic_execution::encode(data)?;
+    crate::__authentic_execution::authentic_execution::handle_output(id, &data)
+}}
@@ -1,7 +1,15 @@
 
-pub fn {name}(data : &[u8]) -> Result<Vec<u8>, Error> {{
-    debug!("REQUEST: {name}");
-	let id : u16 = {id};
+pub fn {name}(data : &[u8]) -> Result<Vec<u8>, crate::__authentic_execution::authentic_execution::Error> {{
+    crate::debug!("REQUEST: {name}");
+	let id : u16 = crate::__outputs::__ids::{name};
 
-    handle_request(id, data)
+    crate::__authentic_execution::authentic_execution::handle_request(id, data)
+}}
+
+pub fn {name}_with_timeout(data : &[u8], timeout : std::time::Duration)
+        -> Result<Vec<u8>, crate::__authentic_execution::authentic_execution::Error> {{
+    crate::debug!("REQUEST: {name}");
+	let id : u16 = crate::__outputs::__ids::{name};
+
+    crate::__authentic_execution::authentic_execution::handle_request_with_timeout(id, data, Some(timeout))
 }}
@@ -0,0 +1,21 @@
+
+pub fn {name}(data : &{arg}) -> Result<{ret}, crate::__authentic_execution::authentic_execution::Error> {{
+    use crate::__authentic_execution::authentic_execution::{{encode, decode, handle_request, Error}};
+
+    crate::debug!("REQUEST: {name}");
+	let id : u16 = crate::__outputs::__ids::{name};
+
+    let response = handle_request(id, &encode(data)?)?;
+    decode(&response).map_err(|_| Error::BadResponse)
+}}
+
+pub fn {name}_with_timeout(data : &{arg}, timeout : std::time::Duration)
+        -> Result<{ret}, crate::__authentic_execution::authentic_execution::Error> {{
+    use crate::__authentic_execution::authentic_execution::{{encode, decode, handle_request_with_timeout, Error}};
+
+    crate::debug!("REQUEST: {name}");
+	let id : u16 = crate::__outputs::__ids::{name};
+
+    let response = handle_request_with_timeout(id, &encode(data)?, Some(timeout))?;
+    decode(&response).map_err(|_| Error::BadResponse)
+}}
@@ -1,16 +1,19 @@
-use std::net::{TcpListener, TcpStream};
+use crate::__net::{Stream, Listener};
 use crate::{debug, info, error};
-use crate::__authentic_execution::authentic_execution::{MODULE_NAME, EM_PORT, MODULE_ID, NUM_THREADS, handle_entrypoint};
-extern crate base64;
+use crate::__authentic_execution::authentic_execution::{MODULE_ADDRESS, EM_ADDRESS, ATTESTATION_PORT, NUM_THREADS,
+    handle_entrypoint, restore_state};
 use threadpool::ThreadPool;
+use zeroize::Zeroizing;
 
 lazy_static! {
-    pub static ref MODULE_KEY: String = remote_attestation().unwrap();
+    pub static ref MODULE_KEY: Zeroizing<Vec<u8>> = remote_attestation().unwrap();
     pub static ref SP_VKEY_PEM: &'static str = "__SP_VKEY_PEM__";
 }
 
-
-fn handle_client(mut stream: TcpStream) {
+/// Serve a single message. Connections are not kept alive: inside an enclave, read
+/// timeouts and non-blocking mode have no effect, so an idle connection would hold
+/// a thread until the peer closes it
+fn handle_client(mut stream: Stream) {
     let payload = match reactive_net::read_message(&mut stream) {
         Ok(p) => p,
         Err(e) => {
@@ -27,11 +30,9 @@ fn handle_client(mut stream: TcpStream) {
 }
 
 
-fn remote_attestation() -> std::io::Result<String> {
+fn remote_attestation() -> std::io::Result<Zeroizing<Vec<u8>>> {
     info!("Waiting for attestation");
-    let port = *EM_PORT + *MODULE_ID;
-
-    let result = match sgx_attestation::do_attestation(port, *SP_VKEY_PEM) {
+    let result = match sgx_attestation::do_attestation(*ATTESTATION_PORT, *SP_VKEY_PEM) {
         Ok(r) => r,
         Err(e) => {
             error!("{:?}", e);
@@ -41,10 +42,10 @@ fn remote_attestation() -> std::io::Result<String> {
 
     info!("Remote attestation succeeded");
 
-    Ok(base64::encode(&result))
+    Ok(Zeroizing::new(result))
 }
 
-fn run_single_thread(listener : TcpListener) {
+fn run_single_thread(listener : Listener) {
     for stream in listener.incoming() {
         //debug!("Received connection");
         match stream {
@@ -55,7 +56,7 @@ fn run_single_thread(listener : TcpListener) {
     }
 }
 
-fn run_multithread(listener : TcpListener) {
+fn run_multithread(listener : Listener) {
     let pool = ThreadPool::new(*NUM_THREADS - 1);
 
     for stream in listener.incoming() {
@@ -69,16 +70,18 @@ fn run_multithread(listener : TcpListener) {
 }
 
 pub fn run() -> std::io::Result<()> {
-    let port = *EM_PORT + *MODULE_ID;
-
     debug!("Waiting for attestation");
     let _ = *MODULE_KEY; // trigger the remote attestation
 
-    // authentic execution
-    let host = format!("127.0.0.1:{}", port); // no one from outside can access SM
+    if let Err(e) = restore_state() {
+        error!("Failed to restore state: {}", e);
+        return Err(std::io::Error::other(e.to_string()));
+    }
 
-    info!("Listening on {}", host);
-    let listener = TcpListener::bind(host)?;
+    // authentic execution
+    info!("Event Manager at {}", *EM_ADDRESS);
+    info!("Listening on {}", *MODULE_ADDRESS);
+    let listener = MODULE_ADDRESS.bind()?;
 
     match *NUM_THREADS {
         0   => panic!("NUM_THREADS is zero"),
@@ -3,6 +3,7 @@ import logging
 import re
 import subprocess
 import json
+import tempfile
 from distutils import dir_util
 import toml
 
@@ -48,80 +49,619 @@ def _check_input_module(path):
     return cargo
 
 
-def _parse_annotations(file):
-    # logging.debug(annotation_file)
+class _Items:
+    """Inputs, outputs, entry points, handlers and requests of the module"""
+
+    def __init__(self):
+        # section -> name -> ID (written to the result JSON), see `assign`
+        self.data = {section: {} for section in conf.START_INDEXES}
+        # section -> name -> path of the function in the crate (e.g., sensors::on_reading)
+        self.paths = {section: {} for section in conf.START_INDEXES}
+        # section -> names of the functions that take typed data
+        self.typed = {section: set() for section in conf.START_INDEXES}
+        # names of the handlers that return `Result<_, ResultCode>`
+        self.fallible = set()
+        # section -> name -> ID given in the declaration
+        self.explicit = {section: {} for section in conf.START_INDEXES}
+        # name -> location (file:line) of its declaration
+        self.locations = {}
+        # name -> section
+        self.sections = {}
+        # name -> (module path, function) of the outputs and requests declared
+        # with annotations
+        self.functions = {}
+
+    def add(self, section, name, path, location, typed=False, res_id=None, fallible=False):
+        what = conf.SECTION_NAMES[section]
+
+        # names are unique in the whole crate, whatever their kind
+        if name in self.locations:
+            other = conf.SECTION_NAMES[self.sections[name]]
+            raise Error(f"{location}: {what} {name} is already declared as "
+                        f"{other} at {self.locations[name]}")
+
+        if name in conf.RESERVED_NAMES:
+            raise Error(f"{location}: {what} {name} has the name of an item "
+                        "of the generated code, choose another name")
+
+        self.data[section][name] = None
+        self.paths[section][name] = path
+        self.locations[name] = location
+        self.sections[name] = section
+
+        if typed:
+            self.typed[section].add(name)
+        if fallible:
+            self.fallible.add(name)
+        if res_id is not None:
+            self.explicit[section][name] = res_id
+
+    def assign(self, lock):
+        # IDs are assigned in this order: explicit IDs, IDs of the lock file, then
+        # the lowest IDs never used before
+        for section, names in self.data.items():
+            explicit = self.explicit[section]
+            locked = lock.get(section, {})
+            retired = lock.get(conf.ID_LOCK_RETIRED, {}).get(section, [])
+            owners = {}
+
+            for name, res_id in explicit.items():
+                self.__check_explicit(section, name, res_id, locked, retired)
+                self.__take(section, name, res_id, owners, "")
+
+            for name in filter(lambda n: n not in explicit and n in locked, names):
+                self.__take(section, name, locked[name], owners,
+                            f" ({conf.ID_LOCK_FILE})")
+
+            used = set(locked.values()) | set(retired)
+            free = (i for i in range(conf.START_INDEXES[section], conf.END_INDEXES[section])
+                    if i not in owners and i not in used)
+
+            for name in filter(lambda n: n not in explicit and n not in locked, names):
+                res_id = next(free, None)
+                if res_id is None:
+                    raise Error(f"{self.locations[name]}: no IDs left for {name}: "
+                                f"too many {section} (range [{conf.START_INDEXES[section]}, "
+                                f"{conf.END_INDEXES[section]}), including removed ones)")
+
+                self.__take(section, name, res_id, owners, "")
+
+    def __check_explicit(self, section, name, res_id, locked, retired):
+        # an explicit ID cannot take over the ID of another name, even a removed one
+        what = conf.SECTION_NAMES[section]
+
+        for other, other_id in locked.items():
+            if other_id == res_id and other != name:
+                raise Error(f"{self.locations[name]}: ID {res_id} of {name} is assigned "
+                            f"to {what} {other} in {conf.ID_LOCK_FILE}")
+
+        if res_id in retired:
+            raise Error(f"{self.locations[name]}: ID {res_id} of {name} belonged to a "
+                        f"removed {what} ({conf.ID_LOCK_FILE}), and IDs are never reused")
+
+    def __take(self, section, name, res_id, owners, origin):
+        start, end = conf.START_INDEXES[section], conf.END_INDEXES[section]
+
+        if not start <= res_id < end:
+            raise Error(f"{self.locations[name]}: ID {res_id}{origin} of {name} "
+                        f"is out of the range of {section} [{start}, {end})")
+
+        if res_id in owners:
+            raise Error(f"{self.locations[name]}: ID {res_id}{origin} of {name} "
+                        f"is already assigned to {owners[res_id]}")
+
+        owners[res_id] = name
+        self.data[section][name] = res_id
+
+
+class _Module:
+    """A file of the crate and the module it defines"""
+
+    def __init__(self, root, file, path, content, masked, annotations):
+        # root folder of the crate
+        self.root = root
+        self.file = file
+        self.path = path
+        self.content = content
+        # comments and literals are blanked out in `masked`
+        self.masked = masked
+        # only block comments and literals are blanked out in `annotations`, so
+        # that annotations (line comments) are only found where they have an effect
+        self.annotations = annotations
+        self.inline = []
+
+        # files of submodules are in the folder of the module (`lib.rs` and
+        # `mod.rs`), or in a folder with the same name of the module
+        if os.path.basename(file) in ["lib.rs", "mod.rs"]:
+            self.dir = os.path.dirname(file)
+        else:
+            self.dir = os.path.splitext(file)[0]
+
+    def inline_at(self, pos):
+        # inline modules (`mod a { .. }`) that contain the given offset
+        return [name for (name, start, end) in self.inline if start <= pos < end]
+
+    def path_at(self, pos):
+        # path of the module at the given offset
+        return self.path + self.inline_at(pos)
+
+    def end_at(self, pos):
+        # end of the module at the given offset: the closing brace of the
+        # innermost inline module, or the end of the file
+        ends = [end for (_, start, end) in self.inline if start <= pos < end]
+        return min(ends) if ends else len(self.content)
+
+    def location(self, pos):
+        # e.g., src/sensors.rs:12
+        file = os.path.relpath(self.file, self.root)
+        return f"{file}:{self.content.count(chr(10), 0, pos) + 1}"
+
+
+def _module_files(src):
+    # Walk the module tree, starting from lib.rs (`#[path]` attributes are not
+    # supported)
+    modules = []
+    queue = [(os.path.join(src, "lib.rs"), [])]
+    p = re.compile(r"\bmod\s+(?:r#)?([_a-zA-Z][_a-zA-Z0-9]*)\s*;", re.ASCII)
+
+    while queue:
+        file, path = queue.pop(0)
+        module = __load_module(os.path.dirname(src), file, path)
+        modules.append(module)
+
+        for result in p.finditer(module.masked):
+            if not module.path and result.group(1) in conf.GENERATED_MODULES:
+                continue
+
+            child = module.inline_at(result.start()) + [result.group(1)]
+            base = os.path.join(module.dir, *child)
+            candidates = [base + ".rs", os.path.join(base, "mod.rs")]
+            found = [c for c in candidates if os.path.exists(c)]
+
+            if not found:
+                logging.warning(f"{module.location(result.start())}: "
+                                f"file of module {result.group(1)} not found")
+                continue
 
+            queue.append((found[0], path + child))
+
+    return modules
+
+
+def __load_module(root, file, path):
     with open(file, "r") as f:
         content = f.read()
 
-    data = {}
+    module = _Module(root, file, path, content, __mask(content),
+                     __mask(content, keep_line_comments=True))
+    module.inline = __inline_modules(module.masked)
 
-    data["inputs"] = __parse_inputs(content)
-    content, data["outputs"] = __parse_outputs(content)
-    data["entrypoints"] = __parse_entrypoints(content)
-    data["handlers"] = __parse_handlers(content)
-    content, data["requests"] = __parse_requests(content)
+    return module
 
-    return content, data
 
+def _parse_annotations(src):
+    items = _Items()
+    lib_content = None
+    modules = _module_files(src)
 
-def __parse_inputs(content):
-    return __parse(content, conf.REGEX_INPUT, conf.START_INPUT_INDEX)
+    for module in modules:
+        content = __parse_module(module, items)
 
+        if module.path:
+            with open(module.file, "w") as f:
+                f.write(content)
+        else:
+            lib_content = content
 
-def __parse_outputs(content):
-    return __parse_inject(content, conf.STUB_OUTPUT, conf.REGEX_OUTPUT, conf.START_OUTPUT_INDEX)
+    __check_rust_items(items, modules, items.locations)
 
+    return lib_content, items
 
-def __parse_entrypoints(content):
-    return __parse(content, conf.REGEX_ENTRY, conf.START_ENTRY_INDEX)
 
+def _parse_threads(content):
+    p = re.compile(conf.REGEX_THREADS, re.MULTILINE | re.ASCII)
+    results = p.findall(__mask(content, keep_line_comments=True))
 
-def __parse_handlers(content):
-    return __parse(content, conf.REGEX_HANDLER, conf.START_HANDLER_INDEX)
+    if not results:
+        return None
 
+    if len(results) > 1:
+        raise Error("sm_threads must be declared only once")
 
-def __parse_requests(content):
-    return __parse_inject(content, conf.STUB_REQUEST, conf.REGEX_REQUEST, conf.START_REQUEST_INDEX)
+    threads = int(results[0])
+    if threads == 0:
+        raise Error("sm_threads must be positive")
 
+    return threads
 
-def __parse(content, regex, start_index):
+
+def __parse_module(module, items):
+    content = module.content
+    injections = []
+    # offsets of the annotations matched
+    matched = set()
+
+    __parse(module, items, matched, "inputs", conf.REGEX_INPUT)
+    injections += __parse_generate(module, items, matched, "outputs", conf.STUB_OUTPUT,
+                                   conf.STUB_OUTPUT_TYPED, conf.REGEX_OUTPUT)
+    __parse(module, items, matched, "entrypoints", conf.REGEX_ENTRY,
+            conf.REGEX_RAW_ENTRY_RET)
+    __parse(module, items, matched, "handlers", conf.REGEX_HANDLER,
+            conf.REGEX_RAW_HANDLER_RET)
+    injections += __parse_generate(module, items, matched, "requests", conf.STUB_REQUEST,
+                                   conf.STUB_REQUEST_TYPED, conf.REGEX_REQUEST)
+
+    # the number of threads is parsed later, only in lib.rs
+    if not module.path:
+        p = re.compile(conf.REGEX_THREADS, re.MULTILINE | re.ASCII)
+        matched.update(result.start() for result in p.finditer(module.annotations))
+
+    __check_annotations(module, matched)
+
+    # import the generated functions at the end of each module (on the line of
+    # the closing brace for inline modules), so that the lines do not change
+    uses = {}
+    for end, path, name in injections:
+        uses.setdefault((end, tuple(path)), []).append(name)
+
+    for (end, path), names in sorted(uses.items(), reverse=True):
+        if path:
+            use = conf.RUST_INCLUDE_OUTPUTS.format(file=__outputs_file(path),
+                                                   names=", ".join(names))
+        else:
+            use = conf.RUST_USE_OUTPUTS.format(names=", ".join(names))
+
+        if end == len(content):
+            use = ("" if content.endswith("\n") or not content else "\n") + use + "\n"
+        else:
+            use += " "
+
+        content = content[:end] + use + content[end:]
+
+    return content
+
+
+def __parse(module, items, matched, section, regex, raw_ret=None):
     p = re.compile(regex, re.MULTILINE | re.ASCII)
-    results = p.findall(content)
 
-    return {v: i for (i, v) in enumerate(results, start_index)}
+    for result in p.finditer(module.annotations):
+        matched.add(result.start())
+        fname = result.group("fname")
+        location = module.location(result.start())
+        path = "::".join(module.path_at(result.start()) + [fname])
+        typed = not re.fullmatch(conf.REGEX_RAW_ARG, result.group("arg"))
 
+        # raw data: the function has to return raw data as well
+        ret = result.groupdict().get("ret") or ""
+        fallible = __handler_result(section, ret)
+        value = fallible.group("ok") if fallible else ret
+        if not typed and raw_ret is not None and not re.fullmatch(raw_ret, value):
+            raise Error(f"{location}: {fname} takes &[u8] but returns {ret or 'nothing'}: "
+                        "use a typed argument to return typed data")
 
-def __parse_inject(content, stub, regex, start_index):
-    res_dict = {}
-    i = 0
-    cnt = 0
+        items.add(section, fname, path, location, typed, __explicit_id(result),
+                  fallible is not None)
 
-    # read stub from file
-    with open(os.path.join(conf.STUBS_FOLDER, stub), "r") as f:
-        fn = f.read()
+
+def __handler_result(section, ret):
+    # handlers can return `Result<T, ResultCode>`
+    if section != "handlers":
+        return None
+
+    return re.fullmatch(conf.REGEX_HANDLER_RESULT, ret)
+
+
+def __parse_generate(module, items, matched, section, stub, typed_stub, regex):
+    # the functions are generated in the __outputs module (or in a file of
+    # src/__outputs, see `_write_outputs`), and imported in the module that declares them
+    injections = []
 
     p = re.compile(regex, re.MULTILINE | re.ASCII)
-    results = p.finditer(content)
+    results = p.finditer(module.annotations)
 
     for result in results:
-        res_id = start_index + i
-        fname = result.group(1)
-        end = result.end()
-        #logging.debug("fname: {} end: {}".format(fname, end))
-        pos = end + cnt
-        inj_fn = fn.format(name=fname, id=res_id)
+        matched.add(result.start())
+        fname = result.group("fname")
+        module_path = module.path_at(result.start())
+        items.add(section, fname, "::".join(module_path + [fname]),
+                  module.location(result.start()), bool(result.group("arg")),
+                  __explicit_id(result))
+        items.functions[fname] = (module_path, __render_stub(
+            stub, typed_stub, fname, result.group("arg"), result.groupdict().get("ret")))
+        for name in __generated_names(section, fname):
+            injections.append((module.end_at(result.start()), module_path, name))
+
+    return injections
+
+
+def __generated_names(section, name):
+    # names of the functions generated for an output or request
+    if section == "requests":
+        return [name, name + conf.REQUEST_TIMEOUT_SUFFIX]
+
+    return [name]
+
+
+def __explicit_id(result):
+    res_id = result.group("id")
+    return int(res_id) if res_id is not None else None
+
+
+def __check_annotations(module, matched):
+    # annotations that are not matched would be silently ignored
+    p = re.compile(conf.REGEX_ANNOTATION, re.MULTILINE | re.ASCII)
+
+    for result in p.finditer(module.annotations):
+        if result.start() in matched:
+            continue
+
+        kind = result.group("kind")
+        hint = conf.ANNOTATION_HINTS.get(kind, "unknown annotation")
+        line = result.group(0).strip()
+
+        raise Error(f"{module.location(result.start())}: invalid annotation "
+                    f"`{line}`: {hint}")
+
+
+def __check_rust_items(items, modules, names):
+    # outputs and requests are generated functions, imported in the module that
+    # declares them: their names cannot be used by other items of that module
+    defined = {}
+    p = re.compile(conf.REGEX_RUST_ITEM, re.ASCII)
+
+    for module in modules:
+        for result in p.finditer(module.masked):
+            # only items at module level (e.g., not methods)
+            pos = result.start()
+            depth = module.masked.count("{", 0, pos) - module.masked.count("}", 0, pos)
+            if depth == len(module.inline_at(pos)):
+                key = (tuple(module.path_at(pos)), result.group("name"))
+                defined.setdefault(key, module.location(pos))
+
+    for name in names:
+        section = items.sections[name]
+        if section not in ["outputs", "requests"]:
+            continue
+
+        what = conf.SECTION_NAMES[section]
+        path = tuple(items.paths[section][name].split("::")[:-1])
+        if (path, name) in defined:
+            raise Error(f"{items.locations[name]}: {what} {name} is already "
+                        f"defined at {defined[(path, name)]}, choose another name")
+
+        for generated in __generated_names(section, name)[1:]:
+            other = defined.get((path, generated), items.locations.get(generated))
+            if other is not None:
+                raise Error(f"{items.locations[name]}: {what} {name} generates "
+                            f"{generated}, which is already defined at {other}, "
+                            "choose another name")
+
+
+def __mask(content, keep_line_comments=False):
+    # replace comments, string and char literals with spaces (newlines are kept)
+    masked = list(content)
+    i = 0
+    n = len(content)
+
+    while i < n:
+        raw = re.match(r'b?r(#*)"', content[i:i + 260])
+        char = re.match(r"b?'(?:\\.|\\x[0-9a-fA-F]{2}|\\u\{[0-9a-fA-F]+\}|[^\\'])'",
+                        content[i:i + 16])
+        ident_before = i > 0 and (content[i - 1].isalnum() or content[i - 1] == "_")
+
+        if content.startswith("//", i):
+            end = content.find("\n", i)
+            end = n if end == -1 else end
+            if keep_line_comments:
+                i = end
+                continue
+        elif content.startswith("/*", i):
+            depth = 0
+            end = i
+            while end < n:
+                if content.startswith("/*", end):
+                    depth += 1
+                    end += 2
+                elif content.startswith("*/", end):
+                    depth -= 1
+                    end += 2
+                    if depth == 0:
+                        break
+                else:
+                    end += 1
+        elif raw and not ident_before:
+            closing = '"' + raw.group(1)
+            end = content.find(closing, i + len(raw.group(0)))
+            end = n if end == -1 else end + len(closing)
+        elif content[i] == '"':
+            end = i + 1
+            while end < n and content[end] != '"':
+                end += 2 if content[end] == "\\" else 1
+            end += 1
+        elif char and not ident_before:
+            end = i + len(char.group(0))
+        else:
+            i += 1
+            continue
+
+        for j in range(i, min(end, n)):
+            if masked[j] != "\n":
+                masked[j] = " "
+        i = end
+
+    return "".join(masked)
+
+
+def __inline_modules(masked):
+    # (name, start, end) of each `mod name { .. }`
+    modules = []
+    p = re.compile(r"\bmod\s+(?:r#)?([_a-zA-Z][_a-zA-Z0-9]*)\s*\{", re.ASCII)
+
+    for result in p.finditer(masked):
+        depth = 0
+        end = result.end() - 1
+        while end < len(masked):
+            if masked[end] == "{":
+                depth += 1
+            elif masked[end] == "}":
+                depth -= 1
+                if depth == 0:
+                    break
+            end += 1
+
+        modules.append((result.group(1), result.end(), end))
+
+    return modules
+
+
+def __render_stub(stub, typed_stub, name, arg=None, ret=None):
+    # typed data if the type of the argument is given. The ID is defined in the
+    # __outputs module
+    with open(os.path.join(conf.STUBS_FOLDER, typed_stub if arg else stub), "r") as f:
+        fn = f.read()
+
+    return fn.format(name=name, arg=arg, ret=ret)
+
+
+def _uses_macros(src):
+    p = re.compile(conf.REGEX_MACROS)
+
+    for root, _, files in os.walk(src):
+        for file in filter(lambda f: f.endswith(".rs"), files):
+            with open(os.path.join(root, file), "r") as f:
+                if p.search(f.read()):
+                    return True
+
+    return False
+
+
+def _collect_metadata(path):
+    # The module is compiled with the metadata env variable set: each macro
+    # writes a description of its item. A temporary target dir is used, so that
+    # all the macros are expanded and the artifacts are not reused afterwards
+    with tempfile.TemporaryDirectory() as tmp:
+        metadata = os.path.join(tmp, "metadata.jsonl")
+        env = dict(os.environ)
+        env[conf.METADATA_ENV] = metadata
+        env["CARGO_TARGET_DIR"] = os.path.join(tmp, "target")
+
+        result = subprocess.run(["cargo", "check", "--manifest-path",
+                                 os.path.join(path, "Cargo.toml")],
+                                env=env, stdout=subprocess.PIPE,
+                                stderr=subprocess.PIPE, universal_newlines=True)
+
+        if result.returncode != 0:
+            logging.error(result.stderr)
+            raise Error("Failed to compile the module to collect the declared items")
+
+        if not os.path.exists(metadata):
+            return []
+
+        with open(metadata, "r") as f:
+            return [json.loads(line) for line in f if line.strip()]
+
+
+def _add_metadata(items, metadata, path):
+    # register the items declared with the macros, after the ones declared with
+    # annotations
+    src = os.path.join(path, "src")
+    modules = {os.path.realpath(m.file): m for m in _module_files(src)}
+    names = []
+
+    for item in metadata:
+        section = conf.METADATA_KINDS[item["kind"]]
+        name = item["name"]
+
+        # paths given to the compiler are relative to the crate
+        module = modules.get(os.path.realpath(os.path.join(path, item["file"])))
+        if module is None:
+            raise Error(f"{item['file']}:{item['line']}: {name} is declared "
+                        "outside of the module tree of lib.rs")
+
+        pos = __line_offset(module.content, item["line"])
+        module_path = "::".join(module.path_at(pos) + [name])
+        items.add(section, name, module_path, module.location(pos), item["typed"],
+                  item.get("id"), __handler_result(section, item["ret"] or "") is not None)
+        names.append(name)
 
-        content = content[:pos] + inj_fn + content[pos:]
-        cnt += len(inj_fn)
+    __check_rust_items(items, modules.values(), names)
 
-        res_dict[fname] = res_id
-        i += 1
 
-    return content, res_dict
+def __line_offset(content, line):
+    pos = 0
+    for _ in range(line - 1):
+        pos = content.index("\n", pos) + 1
 
+    return pos
 
-def _write_module_info(file, name, module_id, data, key=None):
+
+def _write_outputs(src, items):
+    with open(os.path.join(conf.STUBS_FOLDER, conf.STUB_OUTPUTS), "r") as f:
+        content = f.read()
+
+    ids = ""
+    for section in ["outputs", "requests"]:
+        for name, res_id in items.data[section].items():
+            ids += conf.RUST_OUTPUT_ID.format(name=name, id=res_id)
+
+    # functions of the outputs and requests declared with annotations, grouped
+    # by the module that declares them
+    modules = {}
+    for module_path, fn in items.functions.values():
+        modules.setdefault(tuple(module_path), []).append(fn)
+
+    content = content.format(ids=ids, outputs="".join(modules.pop((), [])))
+
+    with open(os.path.join(src, conf.STUB_OUTPUTS), "w") as f:
+        f.write(content)
+
+    # the functions of the other modules are included in those modules (see
+    # `__parse_module`), so that they can use their private types
+    for module_path, functions in modules.items():
+        file = os.path.join(src, conf.OUTPUTS_DIR, __outputs_file(module_path))
+        os.makedirs(os.path.dirname(file), exist_ok=True)
+
+        with open(file, "w") as f:
+            f.write(conf.RUST_OUTPUTS_FILE.format(path="::".join(module_path),
+                                                  content="".join(functions)))
+
+
+def __outputs_file(module_path):
+    # file of src/__outputs with the functions declared in a module
+    return "/".join(module_path) + ".rs"
+
+
+def _load_id_lock(file):
+    if not os.path.exists(file):
+        return {}
+
+    try:
+        return toml.load(file)
+    except Exception as e:
+        raise Error(f"Invalid ID lock file {file}: {e}")
+
+
+def _write_id_lock(file, items, lock):
+    # IDs that are not assigned anymore are retired, so that they are never reused
+    content = {}
+    retired = {}
+
+    for section, names in items.data.items():
+        assigned = set(names.values())
+        old = set(lock.get(section, {}).values()) | \
+            set(lock.get(conf.ID_LOCK_RETIRED, {}).get(section, []))
+
+        content[section] = dict(names)
+        retired[section] = sorted(old - assigned)
+
+    content[conf.ID_LOCK_RETIRED] = retired
+
+    with open(file, "w") as f:
+        f.write(conf.ID_LOCK_HEADER)
+        toml.dump(content, f)
+
+
+def _write_module_info(file, name, module_id, data, key=None, ports=None):
     if key is not None:
         module_info = __helper_write_indexes(
             [(name, "name"), (module_id, "id"), (key, "key")])
@@ -129,7 +669,7 @@ def _write_module_info(file, name, module_id, data, key=None):
         module_info = __helper_write_indexes(
             [(name, "name"), (module_id, "id")])
 
-    content = {**module_info, **data}
+    content = {**module_info, **(ports or {}), **data}
 
     with open(file, 'w', encoding='utf-8') as f:
         json.dump(content, f, ensure_ascii=False, indent=4)
@@ -168,5 +708,54 @@ def _add_fields(dest, src):
             dest[section][key] = src[section][key]
 
 
+def _get_ports(args):
+    if args.module_port is not None:
+        module_port = args.module_port
+    else:
+        module_port = args.emport + args.moduleid
+        if module_port > 65535:
+            raise Error(
+                f"Module port (EM port {args.emport} + module ID {args.moduleid}) "
+                "exceeds 65535, use --module-port to set it explicitly")
+
+    if args.attestation_port is not None:
+        attestation_port = args.attestation_port
+    else:
+        attestation_port = module_port
+
+    return module_port, attestation_port
+
+
+def _get_hosts(args):
+    # explicit addresses have precedence over the defaults of the transport
+    if args.transport == conf.TRANSPORT_UNIX:
+        em_socket = conf.EM_SOCKET_NAME.format(port=args.emport)
+        module_socket = conf.MODULE_SOCKET_NAME.format(id=args.moduleid)
+        default_em = conf.UNIX_SOCKET_PREFIX + \
+            os.path.join(args.socket_dir, em_socket)
+        default_module = conf.UNIX_SOCKET_PREFIX + \
+            os.path.join(args.socket_dir, module_socket)
+    else:
+        default_em = conf.DEFAULT_HOST
+        default_module = conf.DEFAULT_HOST
+
+    em_host = args.em_host or default_em
+    module_host = args.module_host or default_module
+
+    return em_host, module_host
+
+
+def _rust_string(value):
+    # Rust string literal (quotes included) of a value substituted into the stubs
+    escapes = {'"': '\\"', "\\": "\\\\", "\n": "\\n", "\r": "\\r",
+               "\t": "\\t", "\0": "\\0"}
+
+    escaped = "".join(
+        escapes.get(c) or (c if c.isprintable() else f"\\u{{{ord(c):x}}}")
+        for c in value)
+
+    return f'"{escaped}"'
+
+
 def _generate_key(length=conf.KEY_LENGTH):
     return os.urandom(length)